@@ -1,8 +1,14 @@
 // Jackson Coxson & Adam Morgan
 
 use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
 use log::error;
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, OneOrMany};
+
+/// How close to expiring a token can get before it's treated as expired.
+/// Requests can take a while, so don't start one with a token that's about to die.
+pub const EXPIRY_MARGIN_SECS: i64 = 60;
 
 #[derive(Clone, Debug)]
 pub struct BearerToken {
@@ -10,10 +16,34 @@ pub struct BearerToken {
     pub claims: Claims,
 }
 
+#[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Claims {
     #[serde(rename = "missionId")]
     pub mission_id: usize,
+
+    /// Expiration time, unix seconds
+    pub exp: Option<i64>,
+
+    /// Issued at, unix seconds
+    pub iat: Option<i64>,
+
+    /// Not before, unix seconds
+    pub nbf: Option<i64>,
+
+    /// Subject, the logged in user
+    pub sub: Option<String>,
+
+    /// Issuer
+    pub iss: Option<String>,
+
+    /// Audience, which can be one string or a list of them
+    #[serde_as(as = "Option<OneOrMany<_>>")]
+    #[serde(default)]
+    pub aud: Option<Vec<String>>,
+
+    /// Token ID
+    pub jti: Option<String>,
 }
 
 impl BearerToken {
@@ -32,4 +62,64 @@ impl BearerToken {
             ))
         }
     }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.claims.exp?, 0)
+    }
+
+    /// Time left until the token expires, negative if it already has.
+    /// None if the token doesn't say when it expires.
+    pub fn expires_in(&self) -> Option<Duration> {
+        Some(self.expires_at()? - Utc::now())
+    }
+
+    /// Whether the token has expired or will within [EXPIRY_MARGIN_SECS].
+    /// Tokens without an expiration are assumed to be good.
+    pub fn is_expired(&self) -> bool {
+        match self.expires_in() {
+            Some(d) => d < Duration::seconds(EXPIRY_MARGIN_SECS),
+            None => false,
+        }
+    }
+
+    /// An unsigned stand-in for the token that only says which mission it's for.
+    /// It can't be used to log in, but it's enough to replay recorded requests.
+    pub fn redacted(&self) -> String {
+        let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let header = engine.encode(br#"{"alg":"none"}"#);
+        let claims = engine.encode(format!(r#"{{"missionId":{}}}"#, self.claims.mission_id));
+        format!("{header}.{claims}.")
+    }
+
+    /// A human readable description of how long the session has left, for logs
+    pub fn lifetime(&self) -> String {
+        match self.expires_in() {
+            Some(d) if d > Duration::zero() => format!("expires in {}m", d.num_minutes()),
+            Some(d) => format!("expired {}m ago", -d.num_minutes()),
+            None => "has no expiration".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn claims() {
+        let token = super::BearerToken::from_base64(crate::mock::make_token(42, 60 * 60)).unwrap();
+        assert_eq!(token.claims.mission_id, 42);
+        assert_eq!(token.claims.sub.as_deref(), Some(crate::mock::USERNAME));
+        assert_eq!(token.claims.aud, Some(vec!["referralmanager".to_string()]));
+        assert!(!token.is_expired());
+        let left = token.expires_in().unwrap().num_minutes();
+        assert!((58..=60).contains(&left));
+
+        // Inside the margin counts as expired
+        let token = super::BearerToken::from_base64(crate::mock::make_token(42, 30)).unwrap();
+        assert!(token.is_expired());
+
+        let redacted = super::BearerToken::from_base64(token.redacted()).unwrap();
+        assert_eq!(redacted.claims.mission_id, 42);
+        assert_eq!(redacted.claims.sub, None);
+        assert!(!redacted.is_expired());
+    }
 }
@@ -0,0 +1,342 @@
+// Jackson Coxson
+// Records what the church servers said so a strange report can be reproduced offline
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use log::info;
+use reqwest::{Method, Response, ResponseBuilderExt, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{bearer::BearerToken, env::Env, error::ChurchError};
+
+pub const CASSETTE_FILE: &str = "cassette.jsonl";
+const REDACTED: &str = "REDACTED";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Talk to the servers like normal
+    #[default]
+    Off,
+    /// Talk to the servers and write down every response
+    Record,
+    /// Never touch the network, answer from what was written down
+    Replay,
+}
+
+impl CassetteMode {
+    /// Reads ``HTTP_CASSETTE``, which can be ``record`` or ``replay``
+    pub fn from_vars() -> anyhow::Result<Self> {
+        match std::env::var("HTTP_CASSETTE")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "record" => Ok(Self::Record),
+            "replay" => Ok(Self::Replay),
+            "" | "off" => Ok(Self::Off),
+            // Going live by mistake would send real credentials to the church servers
+            other => Err(anyhow::anyhow!(
+                "Unknown HTTP_CASSETTE mode {other}, use record, replay or off"
+            )),
+        }
+    }
+}
+
+/// One request and the response it got, with anything secret taken out
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Interaction {
+    pub method: String,
+    /// The path and query of the request, which is what replays are matched on
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub struct Cassette {
+    mode: CassetteMode,
+    path: PathBuf,
+    /// Open while recording, each interaction is a line
+    file: Mutex<Option<std::fs::File>>,
+    /// Loaded for replays, by method and url. The last response for a request is kept
+    /// around so asking again, like a retry or a second run, gets the same answer.
+    recorded: Mutex<HashMap<(String, String), VecDeque<Interaction>>>,
+}
+
+impl Cassette {
+    /// Opens the cassette in the working path for the env's mode, None if it's off.
+    /// Recording starts a fresh cassette.
+    pub fn open(env: &Env) -> Result<Option<Self>, ChurchError> {
+        let path = PathBuf::from(&env.working_path).join(CASSETTE_FILE);
+        let mut cassette = Self {
+            mode: env.cassette,
+            path,
+            file: Mutex::new(None),
+            recorded: Mutex::new(HashMap::new()),
+        };
+        match env.cassette {
+            CassetteMode::Off => return Ok(None),
+            CassetteMode::Record => {
+                info!("Recording responses to {:?}", cassette.path);
+                *cassette.file.get_mut().unwrap() = Some(std::fs::File::create(&cassette.path)?);
+            }
+            CassetteMode::Replay => {
+                info!("Replaying responses from {:?}", cassette.path);
+                let file = std::io::BufReader::new(std::fs::File::open(&cassette.path)?);
+                let recorded = cassette.recorded.get_mut().unwrap();
+                for line in file.lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let interaction = serde_json::from_str::<Interaction>(&line)?;
+                    recorded
+                        .entry((interaction.method.clone(), interaction.url.clone()))
+                        .or_default()
+                        .push_back(interaction);
+                }
+            }
+        }
+        Ok(Some(cassette))
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.mode == CassetteMode::Replay
+    }
+
+    /// Writes down the response to a request and hands back an identical one to use
+    pub async fn record(
+        &self,
+        method: &Method,
+        url: &Url,
+        res: Response,
+    ) -> Result<Response, ChurchError> {
+        let status = res.status();
+        let final_url = res.url().clone();
+        let headers = res.headers().clone();
+        let body = res.bytes().await?;
+
+        let interaction = Interaction {
+            method: method.to_string(),
+            url: redact_url(url),
+            status: status.as_u16(),
+            // Redacting changes the body, so the length it had doesn't matter
+            headers: headers
+                .iter()
+                .filter(|(k, _)| *k != "content-length" && *k != "transfer-encoding")
+                .map(|(k, v)| {
+                    let v = match k.as_str() {
+                        "set-cookie" | "authorization" | "cookie" => REDACTED.to_string(),
+                        _ => v.to_str().unwrap_or_default().to_string(),
+                    };
+                    (k.to_string(), v)
+                })
+                .collect(),
+            body: redact_body(&String::from_utf8_lossy(&body), is_auth_url(url)),
+        };
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            let mut line = serde_json::to_vec(&interaction)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+        }
+
+        let mut builder = http::Response::builder().status(status).url(final_url);
+        for (k, v) in headers.iter() {
+            builder = builder.header(k, v);
+        }
+        builder
+            .body(body)
+            .map(Response::from)
+            .map_err(|e| std::io::Error::other(e).into())
+    }
+
+    /// The recorded response to a request
+    pub fn replay(&self, method: &Method, url: &Url) -> Result<Response, ChurchError> {
+        let key = (method.to_string(), redact_url(url));
+        let mut recorded = self.recorded.lock().unwrap();
+        let queue = recorded
+            .get_mut(&key)
+            .filter(|q| !q.is_empty())
+            .ok_or_else(|| ChurchError::NotRecorded(format!("{} {}", key.0, key.1)))?;
+        let interaction = if queue.len() > 1 {
+            queue.pop_front().unwrap()
+        } else {
+            queue[0].clone()
+        };
+
+        let mut builder = http::Response::builder()
+            .status(interaction.status)
+            .url(url.clone());
+        for (k, v) in &interaction.headers {
+            builder = builder.header(k, v);
+        }
+        builder
+            .body(interaction.body)
+            .map(Response::from)
+            .map_err(|e| std::io::Error::other(e).into())
+    }
+
+    /// The bearer token from the recorded login, with only the claims that were kept
+    pub fn token(&self) -> Result<BearerToken, ChurchError> {
+        let recorded = self.recorded.lock().unwrap();
+        let token = recorded
+            .iter()
+            .filter(|((_, url), _)| url == "/services/auth")
+            .flat_map(|(_, q)| q)
+            .filter_map(|i| serde_json::from_str::<Value>(&i.body).ok())
+            .find_map(|b| b["token"].as_str().map(|t| t.to_string()))
+            .ok_or_else(|| ChurchError::NotRecorded("a login".to_string()))?;
+        BearerToken::from_base64(token)
+            .map_err(|e| ChurchError::Parse(format!("the recorded bearer token: {e}")))
+    }
+}
+
+/// The path and query of a URL, with anything that looks like a token or code taken out
+pub fn redact_url(url: &Url) -> String {
+    let mut redacted = url.clone();
+    if url.query().is_some() {
+        let pairs = url
+            .query_pairs()
+            .map(|(k, v)| {
+                let v = if is_secret_key(&k) {
+                    REDACTED.to_string()
+                } else {
+                    v.to_string()
+                };
+                (k.to_string(), v)
+            })
+            .collect::<Vec<(String, String)>>();
+        redacted.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    match redacted.query() {
+        Some(q) => format!("{}?{q}", redacted.path()),
+        None => redacted.path().to_string(),
+    }
+}
+
+fn is_secret_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    [
+        "token",
+        "password",
+        "passcode",
+        "secret",
+        "statehandle",
+        "code",
+    ]
+    .iter()
+    .any(|s| key.contains(s))
+}
+
+/// Whether a URL is part of logging in, where responses echo back who is logging in
+fn is_auth_url(url: &Url) -> bool {
+    let path = url.path();
+    path.starts_with("/idp/") || path.starts_with("/oauth2/") || path == "/services/auth"
+}
+
+/// Keys in login responses that carry the username or the account's details
+fn is_identity_key(key: &str) -> bool {
+    ["identifier", "user", "profile", "email", "login"]
+        .iter()
+        .any(|s| key.eq_ignore_ascii_case(s))
+}
+
+/// Takes the secrets out of a response body. The login page embeds its state token in
+/// a script, so HTML carrying one is dropped entirely.
+fn redact_body(body: &str, auth: bool) -> String {
+    match serde_json::from_str::<Value>(body) {
+        Ok(mut json) => {
+            redact_json(&mut json, auth);
+            json.to_string()
+        }
+        Err(_) if body.contains("stateToken") || body.contains("eyJ") => REDACTED.to_string(),
+        Err(_) => body.to_string(),
+    }
+}
+
+fn redact_json(json: &mut Value, auth: bool) {
+    match json {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if k == "token" {
+                    // Keep what's needed to replay without logging in
+                    *v = match v.as_str().map(|t| BearerToken::from_base64(t.to_string())) {
+                        Some(Ok(t)) => Value::String(t.redacted()),
+                        _ => Value::String(REDACTED.to_string()),
+                    };
+                } else if (is_secret_key(k) || (auth && is_identity_key(k))) && !v.is_null() {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(v, auth);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(|v| redact_json(v, auth)),
+        Value::String(s) if s.starts_with("eyJ") => *s = REDACTED.to_string(),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::ResponseBuilderExt;
+    use serde_json::json;
+
+    #[test]
+    fn redaction() {
+        let url = "https://id.example.com/login/token/redirect?stateToken=abc&lang=en"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            super::redact_url(&url),
+            "/login/token/redirect?stateToken=REDACTED&lang=en"
+        );
+
+        let token = crate::mock::make_token(42, 60 * 60);
+        let body = json!({
+            "token": token,
+            "stateHandle": "handle-1",
+            "persons": [{"firstName": "Ana", "session": token}]
+        });
+        let redacted = super::redact_body(&body.to_string(), false);
+        assert!(!redacted.contains(&token));
+        assert!(!redacted.contains("handle-1"));
+        assert!(redacted.contains("Ana"));
+        assert!(!redacted.contains(crate::mock::USERNAME));
+
+        // Login responses echo who is logging in, but a referral's email is kept
+        let login: super::Url = "https://id.example.com/idp/idx/identify".parse().unwrap();
+        assert!(super::is_auth_url(&login));
+        let body = json!({
+            "user": {"value": {"identifier": "elder.smith", "profile": {"email": "e@x.com"}}},
+            "currentAuthenticator": {"value": {"type": "email", "profile": {"email": "e@x.com"}}},
+        });
+        let redacted = super::redact_body(&body.to_string(), true);
+        assert!(!redacted.contains("elder.smith") && !redacted.contains("e@x.com"));
+        assert!(redacted.contains(r#""type":"email""#));
+        let people = json!({"persons": [{"email": "ben@example.com"}]}).to_string();
+        assert!(super::redact_body(&people, false).contains("ben@example.com"));
+
+        let page = "<html><script>var oktaData = {\"stateToken\":\"abc\"};</script></html>";
+        assert_eq!(super::redact_body(page, true), super::REDACTED);
+
+        // Errors end up in logs, so their URLs are redacted too
+        let res = http::Response::builder()
+            .status(500)
+            .url(url)
+            .body("")
+            .unwrap();
+        let e = crate::error::status_error(&res.into()).unwrap();
+        assert_eq!(
+            e.to_string(),
+            "/login/token/redirect?stateToken=REDACTED&lang=en returned 500 Internal Server Error"
+        );
+    }
+}
@@ -5,29 +5,58 @@ use std::{
     io::Write,
     path::PathBuf,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Context;
-use chrono::{NaiveDateTime, NaiveTime, Duration};
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use dialoguer::{theme::ColorfulTheme, Input, Select};
 use log::{info, warn};
-use reqwest::{redirect::Policy, Client};
+use reqwest::{
+    header::{ACCEPT, AUTHORIZATION, SET_COOKIE},
+    redirect::Policy,
+    Client, RequestBuilder, Response,
+};
 use reqwest_cookie_store::CookieStoreMutex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::{bearer::BearerToken, env, persons};
+use crate::{
+    bearer::BearerToken,
+    cassette::Cassette,
+    env,
+    error::{check_status, status_error, ChurchError},
+    idx, persons,
+    retry::{RetryAction, RetryCounters, RetryStats},
+    snapshots,
+};
 
 pub const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/136.0.0.0 Safari/537.36";
-const MAX_RETRIES: u8 = 3;
+const MAX_LOGIN_STEPS: u8 = 10;
 
 #[derive(Debug)]
 pub struct ChurchClient {
     http_client: Client,
     cookie_store: Arc<CookieStoreMutex>,
+    /// Set when the API refreshed a cookie that hasn't been saved yet
+    cookies_changed: AtomicBool,
     pub env: env::Env,
-    bearer_token: Option<BearerToken>,
+    bearer_token: Mutex<Option<BearerToken>>,
+    /// Held for the whole login flow so concurrent requests don't all log in at once
+    login_lock: tokio::sync::Mutex<()>,
+    /// Bumped after every successful login
+    login_generation: AtomicUsize,
+    /// What the retry policy has had to do since the counts were last taken
+    retries: RetryCounters,
+    /// Set when recording or replaying responses
+    cassette: Option<Cassette>,
+    /// How parsing the last people list went
+    people_diagnostics: Mutex<persons::ParseDiagnostics>,
+    /// How parsing timelines has gone since these were last taken
+    timeline_diagnostics: Mutex<persons::ParseDiagnostics>,
     pub holly_config: Option<crate::holly::config::Config>,
 }
 
@@ -37,8 +66,15 @@ impl ChurchClient {
         let bearer_path = PathBuf::from_str(&env.working_path)?.join("bearer.token");
         let cookies_path = PathBuf::from_str(&env.working_path)?.join("cookies.json");
 
-        let bearer_token = if let Ok(b) = std::fs::read_to_string(&bearer_path) {
-            Some(BearerToken::from_base64(b)?)
+        let cassette = Cassette::open(&env)?;
+        let replaying = cassette.as_ref().is_some_and(|c| c.is_replay());
+
+        let bearer_token = if replaying {
+            None
+        } else if let Ok(b) = std::fs::read_to_string(&bearer_path) {
+            let token = BearerToken::from_base64(b)?;
+            info!("Saved bearer token {}", token.lifetime());
+            Some(token)
         } else {
             info!("No bearer token saved");
             None
@@ -66,7 +102,7 @@ impl ChurchClient {
                 if a.previous().len() > 2 {
                     a.stop()
                 } else {
-                    info!("Redirecting to {}", a.url());
+                    info!("Redirecting to {}", crate::cassette::redact_url(a.url()));
                     a.follow()
                 }
             }))
@@ -74,20 +110,32 @@ impl ChurchClient {
             .build()
             .expect("Couldn't build the HTTP client");
 
+        info!(
+            "Using referral manager at {} and church ID at {}",
+            env.endpoints.referral_manager, env.endpoints.church_id
+        );
         let holly_config = crate::holly::config::Config::potential_load(&env).await?;
 
         Ok(Self {
             http_client,
             cookie_store,
+            cookies_changed: AtomicBool::new(false),
             env,
-            bearer_token,
+            bearer_token: Mutex::new(bearer_token),
+            login_lock: tokio::sync::Mutex::new(()),
+            login_generation: AtomicUsize::new(0),
+            retries: RetryCounters::default(),
+            cassette,
+            people_diagnostics: Mutex::default(),
+            timeline_diagnostics: Mutex::default(),
             holly_config,
         })
     }
 
-    pub async fn save_cookies(&self) -> anyhow::Result<()> {
+    pub async fn save_cookies(&self) -> Result<(), ChurchError> {
         info!("Saving cookies");
-        let cookies_path = PathBuf::from_str(&self.env.working_path)?.join("cookies.json");
+        self.cookies_changed.store(false, Ordering::SeqCst);
+        let cookies_path = PathBuf::from(&self.env.working_path).join("cookies.json");
         let mut writer = std::fs::File::create(&cookies_path)
             .map(std::io::BufWriter::new)
             .unwrap();
@@ -98,9 +146,19 @@ impl ChurchClient {
         Ok(())
     }
 
-    async fn write_bearer_token(&self, token: &str) -> anyhow::Result<()> {
+    /// Saves the cookies if the API changed them since they were last saved. Saving after
+    /// every response would rewrite the file hundreds of times a run, so this is done once a
+    /// command or Holly's list is finished.
+    pub async fn save_changed_cookies(&self) -> Result<(), ChurchError> {
+        if self.cookies_changed.load(Ordering::SeqCst) && !self.replaying() {
+            self.save_cookies().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_bearer_token(&self, token: &str) -> Result<(), ChurchError> {
         info!("Saving bearer token");
-        let bearer_path = PathBuf::from_str(&self.env.working_path)?.join("bearer.token");
+        let bearer_path = PathBuf::from(&self.env.working_path).join("bearer.token");
         let mut writer = std::fs::File::create(&bearer_path)
             .map(std::io::BufWriter::new)
             .unwrap();
@@ -108,20 +166,62 @@ impl ChurchClient {
         Ok(())
     }
 
+    fn bearer_token(&self) -> Option<BearerToken> {
+        self.bearer_token.lock().unwrap().clone()
+    }
+
     /// Logs into churchofjesuschrist.org
-    pub async fn login(&mut self) -> anyhow::Result<BearerToken> {
+    pub async fn login(&self) -> Result<BearerToken, ChurchError> {
+        let _guard = self.login_lock.lock().await;
+        self.login_inner().await
+    }
+
+    /// Logs in again unless another request already did since `generation` was read.
+    /// This keeps a burst of failed parallel requests from each logging in.
+    async fn relogin(&self, generation: usize) -> Result<(), ChurchError> {
+        let _guard = self.login_lock.lock().await;
+        if self.login_generation.load(Ordering::SeqCst) != generation {
+            info!("Someone else already logged in");
+            return Ok(());
+        }
+        self.login_inner().await?;
+        Ok(())
+    }
+
+    /// Gets the bearer token, logging in first if there isn't one or it's about to expire
+    async fn valid_token(&self) -> Result<BearerToken, ChurchError> {
+        let generation = self.login_generation.load(Ordering::SeqCst);
+        match self.bearer_token() {
+            Some(t) if !t.is_expired() => return Ok(t),
+            Some(t) => info!("Bearer token {}, logging in again", t.lifetime()),
+            None => info!("No bearer token, logging in"),
+        }
+        self.relogin(generation).await?;
+        self.bearer_token()
+            .ok_or_else(|| ChurchError::Auth("Logging in didn't give a bearer token".to_string()))
+    }
+
+    async fn login_inner(&self) -> Result<BearerToken, ChurchError> {
+        if let Some(cassette) = self.cassette.as_ref().filter(|_| self.replaying()) {
+            let token = cassette.token()?;
+            info!(
+                "Using the recorded login for mission {}",
+                token.claims.mission_id
+            );
+            *self.bearer_token.lock().unwrap() = Some(token.clone());
+            self.login_generation.fetch_add(1, Ordering::SeqCst);
+            return Ok(token);
+        }
+
         info!("Logging into referral manager");
         self.cookie_store.lock().unwrap().clear();
 
         // Get the inital login page
         info!("Loading the initial login page");
         let res = self
-            .http_client
-            .get("https://referralmanager.churchofjesuschrist.org")
-            .send()
-            .await?
-            .text()
+            .send(self.http_client.get(&self.env.endpoints.referral_manager))
             .await?;
+        let res = check_status(res)?.text().await?;
 
         // Extract the JSON embedded in the HTML
         let start_token = "\"stateToken\":\"";
@@ -129,185 +229,384 @@ impl ChurchClient {
 
         let start_index = res
             .find(start_token)
-            .ok_or_else(|| anyhow::anyhow!("stateToken not found in response"))?
+            .ok_or_else(|| ChurchError::Parse("the login page, no stateToken".to_string()))?
             + start_token.len();
 
         let end_index = res[start_index..]
             .find(end_token)
-            .ok_or_else(|| anyhow::anyhow!("End token not found in response"))?
+            .ok_or_else(|| ChurchError::Parse("the login page, no end token".to_string()))?
             + start_index;
 
         // Ensure the indices are valid
         if start_index >= end_index {
-            return Err(anyhow::anyhow!("Invalid indices for stateToken extraction"));
+            return Err(ChurchError::Parse(
+                "the login page, invalid stateToken indices".to_string(),
+            ));
         }
 
         let state_token = &res[start_index..end_index];
-        let state_token = decode_escape_sequences(state_token)?;
-        let state_token: String = serde_json::from_str(&format!("\"{state_token}\"")).unwrap();
+        let state_token = decode_escape_sequences(state_token);
+        let state_token: String = serde_json::from_str(&format!("\"{state_token}\""))?;
 
-        #[derive(Deserialize)]
-        struct StateHandle {
-            #[serde(rename = "stateHandle")]
-            state_handle: String,
-        }
         // Trade the state token for the state handle
         info!("Trading the token for the state handle");
-        let state_handle = self
-            .http_client
-            .post("https://id.churchofjesuschrist.org/idp/idx/introspect")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .body(format!("{{\"stateToken\": \"{state_token}\"}}"))
-            .send()
-            .await?
-            .json::<StateHandle>()
+        let mut state_handle = self
+            .idx_post("introspect", json!({"stateToken": state_token}))
             .await?
-            .state_handle;
+            .state_handle
+            .ok_or_else(|| {
+                ChurchError::Parse("the introspect response, no stateHandle".to_string())
+            })?;
 
         // Send the username
         info!("Sending the username");
-        let body = json!({
-            "stateHandle": state_handle,
-            "identifier": self.env.church_username
-        })
-        .to_string();
-        let state_handle = self
-            .http_client
-            .post("https://id.churchofjesuschrist.org/idp/idx/identify")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .body(body)
-            .send()
-            .await?
-            .json::<StateHandle>()
-            .await?
-            .state_handle;
-
-        // Send the password
-        #[derive(Deserialize)]
-        struct PasswordResponse {
-            success: SuccessResponse,
-        }
-
-        #[derive(Deserialize)]
-        struct SuccessResponse {
-            href: String,
-        }
+        let mut res = self
+            .idx_post(
+                "identify",
+                json!({
+                    "stateHandle": state_handle,
+                    "identifier": self.env.church_username
+                }),
+            )
+            .await?;
 
-        info!("Sending the password");
-        let body = json!({
-            "stateHandle": state_handle,
-            "credentials": {
-                "passcode": self.env.church_password
+        // Answer whatever Okta asks for until it's happy. Without MFA this is just the password.
+        let mut password_sent = false;
+        let mut steps = 0;
+        let href = loop {
+            steps += 1;
+            if steps > MAX_LOGIN_STEPS {
+                return Err(ChurchError::Auth("Login took too many steps".to_string()));
             }
-        })
-        .to_string();
-        let res = self
-            .http_client
-            .post("https://id.churchofjesuschrist.org/idp/idx/challenge/answer")
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .body(body)
-            .send()
-            .await?
-            .json::<PasswordResponse>()
-            .await?;
+            if let Some(h) = &res.state_handle {
+                state_handle = h.clone();
+            }
+            res = match res.next_step()? {
+                idx::Step::Success(href) => break href,
+                idx::Step::Password => {
+                    info!("Sending the password");
+                    password_sent = true;
+                    self.idx_post(
+                        "challenge/answer",
+                        json!({
+                            "stateHandle": state_handle,
+                            "credentials": {
+                                "passcode": self.env.church_password
+                            }
+                        }),
+                    )
+                    .await?
+                }
+                idx::Step::Select(authenticators) => {
+                    let authenticator = self.choose_authenticator(authenticators, password_sent)?;
+                    info!("Selecting the {} authenticator", authenticator.name());
+                    let mut selection = json!({"id": authenticator.id});
+                    if let Some(method) = authenticator.method_type() {
+                        selection["methodType"] = json!(method);
+                    }
+                    self.idx_post(
+                        "challenge",
+                        json!({
+                            "stateHandle": state_handle,
+                            "authenticator": selection
+                        }),
+                    )
+                    .await?
+                }
+                idx::Step::Code(authenticator) => {
+                    let code = match (&authenticator.kind, &self.env.church_totp_secret) {
+                        (idx::AuthenticatorKind::App, Some(secret)) => {
+                            info!("Generating a code for {}", authenticator.name());
+                            idx::totp_code(secret)?
+                        }
+                        _ => Input::<String>::with_theme(&ColorfulTheme::default())
+                            .with_prompt(format!(
+                                "Enter the verification code from {}",
+                                authenticator.name()
+                            ))
+                            .interact_text()?,
+                    };
+                    info!("Sending the verification code");
+                    self.idx_post(
+                        "challenge/answer",
+                        json!({
+                            "stateHandle": state_handle,
+                            "credentials": {
+                                "passcode": code.trim()
+                            }
+                        }),
+                    )
+                    .await?
+                }
+            };
+        };
 
         // Set cookies
         info!("Getting the success href");
-        self.http_client.get(res.success.href).send().await?;
+        check_status(self.send(self.http_client.get(href)).await?)?;
 
         // Get the bearer token
         info!("Getting the bearer token");
-        let token = self
-            .http_client
-            .get("https://referralmanager.churchofjesuschrist.org/services/auth")
-            .header("Accept", "application/json")
-            .send()
-            .await?
-            .json::<serde_json::Value>()
-            .await?["token"]
-            .clone();
+        let res = self
+            .send(
+                self.http_client
+                    .get(format!(
+                        "{}/services/auth",
+                        self.env.endpoints.referral_manager
+                    ))
+                    .header("Accept", "application/json"),
+            )
+            .await?;
+        let token = check_status(res)?.json::<serde_json::Value>().await?["token"].clone();
         let token = match token {
             serde_json::Value::String(t) => Ok(t),
-            _ => Err(anyhow::anyhow!("No token in response json")),
+            _ => Err(ChurchError::Parse(
+                "the auth response, no token".to_string(),
+            )),
         }?;
 
         self.save_cookies().await?;
         self.write_bearer_token(&token).await?;
 
-        let token = BearerToken::from_base64(token)?;
-        self.bearer_token = Some(token.clone());
+        let token = BearerToken::from_base64(token)
+            .map_err(|e| ChurchError::Parse(format!("the bearer token: {e}")))?;
+        info!("Logged in, session {}", token.lifetime());
+        *self.bearer_token.lock().unwrap() = Some(token.clone());
+        self.login_generation.fetch_add(1, Ordering::SeqCst);
 
         Ok(token)
     }
 
+    fn replaying(&self) -> bool {
+        self.cassette.as_ref().is_some_and(|c| c.is_replay())
+    }
+
+    /// Sends a request, going through the cassette when recording or replaying
+    async fn send(&self, request: RequestBuilder) -> Result<Response, ChurchError> {
+        let request = request.build()?;
+        match &self.cassette {
+            Some(cassette) if cassette.is_replay() => {
+                cassette.replay(request.method(), request.url())
+            }
+            Some(cassette) => {
+                let method = request.method().clone();
+                let url = request.url().clone();
+                let res = self.http_client.execute(request).await?;
+                cassette.record(&method, &url, res).await
+            }
+            None => Ok(self.http_client.execute(request).await?),
+        }
+    }
+
+    /// Posts a step of the IDX login flow. Okta answers wrong passwords and codes with an
+    /// error status but the same JSON shape, so the body is read either way.
+    async fn idx_post(
+        &self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<idx::IdxResponse, ChurchError> {
+        let res = self
+            .send(
+                self.http_client
+                    .post(format!("{}/idp/idx/{path}", self.env.endpoints.church_id))
+                    .header("Content-Type", "application/json")
+                    .header("Accept", "application/json")
+                    .body(body.to_string()),
+            )
+            .await?;
+        // Wrong passwords and codes come back as a 4xx with the usual JSON, so keep reading those
+        if let Some(e) = status_error(&res) {
+            if !matches!(&e, ChurchError::Status { status, .. } if status.is_client_error()) {
+                return Err(e);
+            }
+        }
+        let status = res.status();
+        let res = res
+            .json::<idx::IdxResponse>()
+            .await
+            .map_err(|e| ChurchError::Parse(format!("the {path} response ({status}): {e}")))?;
+        if let Some(e) = res.errors() {
+            return Err(ChurchError::Auth(format!("{path}: {e}")));
+        }
+        Ok(res)
+    }
+
+    /// Picks which authenticator to be challenged with.
+    /// The password always goes first, then a TOTP app if we have the secret for it.
+    /// Otherwise the user gets to choose.
+    fn choose_authenticator(
+        &self,
+        authenticators: Vec<idx::Authenticator>,
+        password_sent: bool,
+    ) -> Result<idx::Authenticator, ChurchError> {
+        let (password, mut others): (Vec<_>, Vec<_>) = authenticators
+            .into_iter()
+            .partition(|a| a.kind == idx::AuthenticatorKind::Password);
+        if !password_sent {
+            if let Some(p) = password.into_iter().next() {
+                return Ok(p);
+            }
+        }
+        if self.env.church_totp_secret.is_some() {
+            if let Some(i) = others
+                .iter()
+                .position(|a| a.kind == idx::AuthenticatorKind::App)
+            {
+                return Ok(others.swap_remove(i));
+            }
+        }
+        match others.len() {
+            0 => Err(ChurchError::Auth(
+                "No usable authenticators to choose from".to_string(),
+            )),
+            1 => Ok(others.remove(0)),
+            _ => {
+                let names = others.iter().map(|a| a.name()).collect::<Vec<String>>();
+                let selection = Select::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Your account needs another verification. Choose a method")
+                    .default(0)
+                    .items(&names)
+                    .interact()?;
+                Ok(others.remove(selection))
+            }
+        }
+    }
+
     /// Gets the list of everyone from the referral manager. This is a HUGE request at roughly 8mb in the CSDM
-    pub async fn get_people_list(&mut self) -> anyhow::Result<Vec<persons::Person>> {
+    pub async fn get_people_list(&self) -> Result<Vec<persons::Person>, ChurchError> {
         info!("Getting the people list from referral manager");
+        let (list, diagnostics) = self
+            .api_get::<persons::PeopleList>("Getting the people list", |token| {
+                format!(
+                    "/services/people/mission/{}?includeDroppedPersons=true",
+                    token.claims.mission_id
+                )
+            })
+            .await?
+            .into_parts();
+        info!("Received {} people from referral manager", list.len());
+        *self.people_diagnostics.lock().unwrap() = diagnostics;
+        Ok(list)
+    }
+
+    /// Gets JSON from referral manager's API. Every call to `/services` goes through here so
+    /// they all send the bearer token and session cookies, note when cookies change, log in
+    /// again when the session dies and retry by the [RetryPolicy](crate::retry::RetryPolicy).
+    /// The path can use the token, since some endpoints need the mission ID.
+    async fn api_get<T: serde::de::DeserializeOwned>(
+        &self,
+        what: &str,
+        path: impl Fn(&BearerToken) -> String,
+    ) -> Result<T, ChurchError> {
+        self.with_retries(what, |token| {
+            let path = path(&token);
+            async move {
+                let started = std::time::Instant::now();
+                let res = self
+                    .send(
+                        self.http_client
+                            .get(format!("{}{path}", self.env.endpoints.referral_manager))
+                            .header(AUTHORIZATION, format!("Bearer {}", token.token))
+                            .header(ACCEPT, "application/json"),
+                    )
+                    .await?;
+                info!(
+                    "GET {path} answered {} in {}ms",
+                    res.status(),
+                    started.elapsed().as_millis()
+                );
+                if res.headers().contains_key(SET_COOKIE) {
+                    self.cookies_changed.store(true, Ordering::SeqCst);
+                }
+                Ok(check_status(res)?.json::<T>().await?)
+            }
+        })
+        .await
+    }
+
+    /// Runs a request with a good bearer token, retrying it by the env's [RetryPolicy](crate::retry::RetryPolicy).
+    /// Bad sessions log in again, struggling servers get waited out, and anything else is given up on.
+    /// Logging in again is part of the next try, so a login that fails is handled the same way.
+    async fn with_retries<T, F, Fut>(&self, what: &str, mut request: F) -> Result<T, ChurchError>
+    where
+        F: FnMut(BearerToken) -> Fut,
+        Fut: std::future::Future<Output = Result<T, ChurchError>>,
+    {
+        let policy = &self.env.retry;
         let mut tries = 0;
+        // The login generation to log in again from before the next try
+        let mut relogin = None;
 
-        while tries < MAX_RETRIES {
-            let token = match &self.bearer_token {
-                Some(t) => t,
-                None => &self.login().await?,
-            };
+        loop {
             tries += 1;
-            if let Ok(list) = self.http_client.get(format!("https://referralmanager.churchofjesuschrist.org/services/people/mission/{}?includeDroppedPersons=true", token.claims.mission_id))
-            .header("Authorization", format!("Bearer {}", token.token))
-            .send().await {
-                if let Ok(list) = list.json::<serde_json::Value>().await {
-                    let list = persons::Person::parse_lossy(list);
-                    info!("Received {} people from referral manager", list.len());
-                    return Ok(list);
-                } else {
-                    warn!("Getting the people list failed at JSON parse");
-                    self.bearer_token = None;
+            let mut generation = self.login_generation.load(Ordering::SeqCst);
+            let login = match relogin {
+                Some(generation) => self.relogin(generation).await,
+                None => Ok(()),
+            };
+            let res = match login {
+                Ok(()) => {
+                    relogin = None;
+                    match self.valid_token().await {
+                        Ok(token) => {
+                            generation = self.login_generation.load(Ordering::SeqCst);
+                            request(token).await
+                        }
+                        Err(e) => Err(e),
+                    }
                 }
+                // The session is still bad, so the next try has to log in again too
+                Err(e) => Err(e),
+            };
+            let e = match res {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            let action = RetryAction::from(&e);
+            if action == RetryAction::GiveUp {
+                self.retries.gave_up();
+                return Err(e);
+            }
+            if tries >= policy.max_tries {
+                warn!("{what} failed: {e}, giving up");
+                self.retries.gave_up();
+                return Err(ChurchError::RetriesExhausted {
+                    attempts: tries,
+                    last: Box::new(e),
+                });
+            }
+            self.retries.retried(action, &e);
+            if action == RetryAction::Relogin {
+                warn!("{what} failed: {e}, logging in again");
+                relogin = Some(generation);
             } else {
-                warn!("Getting the people list failed at the request");
-                self.bearer_token = None;
+                let delay = policy.delay(tries, &e);
+                warn!("{what} failed: {e}, retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
             }
         }
-        Err(anyhow::anyhow!("Max tries exceeded"))
+    }
+
+    /// How many retries requests have needed since this was last called
+    pub fn take_retry_stats(&self) -> RetryStats {
+        self.retries.take()
     }
 
     /// Gets a cached list from referral manager to save trips to church servers.
     /// A cache will be considered 'hit' if the list is less than an hour old.
-    pub async fn get_cached_people_list(&mut self) -> anyhow::Result<Vec<persons::Person>> {
-        let lists_path = PathBuf::from_str(&self.env.working_path)?.join("people_lists");
-        std::fs::create_dir_all(&lists_path)?;
-
-        let now = SystemTime::now();
-        let now = now
-            .duration_since(UNIX_EPOCH)
-            .context("Your clock is wrong")?
-            .as_secs();
-
-        // Read all the entries in the cache
-        for f in std::fs::read_dir(&lists_path)? {
-            match f {
-                Ok(f) if f.file_type()?.is_file() => {
-                    if let Ok(file_name) = f.file_name().into_string() {
-                        if let Some(file_name) = file_name.split_once('.') {
-                            if let Ok(timestamp) = file_name.0.parse::<u64>() {
-                                if let Some(diff) = now.checked_sub(timestamp) {
-                                    if diff < 60 * 60 {
-                                        info!("Cache hit");
-                                        return Ok(persons::Person::parse_lossy(
-                                            serde_json::from_str(
-                                                &std::fs::read_to_string(f.path()).unwrap(),
-                                            )?,
-                                        ));
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => (),
-            }
+    pub async fn get_cached_people_list(&self) -> Result<Vec<persons::Person>, ChurchError> {
+        let lists_path = PathBuf::from(&self.env.working_path).join(snapshots::SNAPSHOTS_DIR);
+        let now = unix_now()?;
+
+        let snapshots = snapshots::Snapshot::list(&self.env)?;
+        if let Some(snapshot) = snapshots
+            .last()
+            .filter(|s| now.saturating_sub(s.taken) < 60 * 60)
+        {
+            info!("Cache hit");
+            let (list, diagnostics) = snapshot.load()?.into_parts();
+            *self.people_diagnostics.lock().unwrap() = diagnostics;
+            return Ok(list);
         }
         info!("Cache miss");
         let list = self.get_people_list().await?;
@@ -316,52 +615,141 @@ impl ChurchClient {
             .create(true)
             .truncate(true)
             .open(lists_path.join(format!("{now}.json")))?;
-        serde_json::to_writer(file, &json!({"persons": &list}))?;
+        serde_json::to_writer(
+            std::io::BufWriter::new(file),
+            &persons::CachedPeopleList {
+                persons: &list,
+                diagnostics: &self.people_diagnostics(),
+            },
+        )?;
         Ok(list)
     }
 
+    /// How parsing the last people list went, whether it came from the servers or the cache
+    pub fn people_diagnostics(&self) -> persons::ParseDiagnostics {
+        self.people_diagnostics.lock().unwrap().clone()
+    }
+
+    /// How parsing timelines has gone since this was last called
+    pub fn take_timeline_diagnostics(&self) -> persons::ParseDiagnostics {
+        std::mem::take(&mut *self.timeline_diagnostics.lock().unwrap())
+    }
+
+    /// What parsing found across every timeline in the cache, without touching the servers
+    pub fn cached_timeline_diagnostics(&self) -> Result<persons::ParseDiagnostics, ChurchError> {
+        let mut diagnostics = persons::ParseDiagnostics::default();
+        let timelines_path = PathBuf::from(&self.env.working_path).join("timelines");
+        if !std::fs::exists(&timelines_path)? {
+            return Ok(diagnostics);
+        }
+        for f in std::fs::read_dir(&timelines_path)? {
+            let s = std::fs::read_to_string(f?.path())?;
+            if let Ok(cached) = serde_json::from_str::<CachedTimeline>(&s) {
+                diagnostics.merge(&cached.diagnostics);
+            }
+        }
+        Ok(diagnostics)
+    }
+
     pub async fn get_person_timeline(
-        &mut self,
+        &self,
+        person: &persons::Person,
+    ) -> Result<Vec<persons::TimelineEvent>, ChurchError> {
+        Ok(self.fetch_person_timeline(person).await?.0)
+    }
+
+    async fn fetch_person_timeline(
+        &self,
         person: &persons::Person,
-    ) -> anyhow::Result<Vec<persons::TimelineEvent>> {
+    ) -> Result<(Vec<persons::TimelineEvent>, persons::ParseDiagnostics), ChurchError> {
         info!("Getting timeline for {}", person.guid);
-        let mut tries = 0;
+        let (list, diagnostics) = self
+            .api_get::<persons::LossyList<persons::TimelineEvent>>(
+                &format!("Getting the timeline for {}", person.guid),
+                |_| format!("/services/progress/timeline/{}", person.guid),
+            )
+            .await?
+            .into_parts();
+        info!(
+            "Received {} timeline events from referral manager",
+            list.len()
+        );
+        self.timeline_diagnostics
+            .lock()
+            .unwrap()
+            .merge(&diagnostics);
+        Ok((list, diagnostics))
+    }
 
-        while tries < MAX_RETRIES {
-            tries += 1;
-            if let Ok(list) = self
-                .http_client
-                .get(format!(
-                    "https://referralmanager.churchofjesuschrist.org/services/progress/timeline/{}",
-                    person.guid
-                ))
-                .send()
-                .await
-            {
-                if let Ok(list) = list.json::<serde_json::Value>().await {
-                    let list = persons::TimelineEvent::parse_lossy(list);
-                    info!(
-                        "Received {} timeline events from referral manager",
-                        list.len()
-                    );
-                    return Ok(list);
-                } else {
-                    warn!("Getting the timeline events list failed at JSON parse");
-                    self.login().await?;
+    /// Gets a person's timeline from the cache in the working path, if it's still good.
+    /// A cached timeline is thrown out once the person's statuses, assigned date or area
+    /// change, or once it's older than the configured TTL.
+    pub async fn get_cached_person_timeline(
+        &self,
+        person: &persons::Person,
+    ) -> Result<Vec<persons::TimelineEvent>, ChurchError> {
+        // The GUID comes from the server, so only a plain one is trusted as a file name
+        if person.guid.is_empty()
+            || !person
+                .guid
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            warn!(
+                "Not caching the timeline for {:?}, it isn't a GUID",
+                person.guid
+            );
+            return self.get_person_timeline(person).await;
+        }
+        let timelines_path = PathBuf::from(&self.env.working_path).join("timelines");
+        std::fs::create_dir_all(&timelines_path)?;
+        let path = timelines_path.join(format!("{}.json", person.guid));
+
+        let now = unix_now()?;
+        let snapshot = TimelineSnapshot::from(person);
+
+        if let Ok(s) = std::fs::read_to_string(&path) {
+            match serde_json::from_str::<CachedTimeline>(&s) {
+                Ok(cached)
+                    if cached.snapshot == snapshot
+                        && now.saturating_sub(cached.fetched)
+                            < self.env.timeline_cache_hours.saturating_mul(60 * 60) =>
+                {
+                    info!("Timeline cache hit for {}", person.guid);
+                    self.timeline_diagnostics
+                        .lock()
+                        .unwrap()
+                        .merge(&cached.diagnostics);
+                    return Ok(cached.events);
                 }
-            } else {
-                warn!("Getting the timeline events list failed at the request");
-                self.login().await?;
+                Ok(_) => info!("Cached timeline for {} is stale", person.guid),
+                Err(e) => warn!("Unable to read cached timeline for {}: {e:?}", person.guid),
             }
         }
-        Err(anyhow::anyhow!("Max tries exceeded"))
+
+        let (events, diagnostics) = self.fetch_person_timeline(person).await?;
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        serde_json::to_writer(
+            file,
+            &CachedTimeline {
+                fetched: now,
+                snapshot,
+                events: events.clone(),
+                diagnostics,
+            },
+        )?;
+        Ok(events)
     }
 
     pub async fn get_person_last_contact(
-        &mut self,
+        &self,
         person: &persons::Person,
-    ) -> anyhow::Result<Option<NaiveDateTime>> {
-        let timeline = self.get_person_timeline(person).await?;
+    ) -> Result<Option<NaiveDateTime>, ChurchError> {
+        let timeline = self.get_cached_person_timeline(person).await?;
         for item in timeline {
             match item.item_type {
                 persons::TimelineItemType::Contact | persons::TimelineItemType::Teaching => {
@@ -377,10 +765,10 @@ impl ChurchClient {
     }
 
     pub async fn get_person_contact_time(
-        &mut self,
+        &self,
         person: &persons::Person,
-    ) -> anyhow::Result<Option<usize>> {
-        let mut timeline = self.get_person_timeline(person).await?;
+    ) -> Result<Option<usize>, ChurchError> {
+        let mut timeline = self.get_cached_person_timeline(person).await?;
         timeline.reverse();
         let mut referral_sent = None;
         let mut last_contact = None;
@@ -425,8 +813,46 @@ impl ChurchClient {
     }
 }
 
+/// The parts of a person that change when something happens on their timeline
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct TimelineSnapshot {
+    referral_status: persons::ReferralStatus,
+    person_status: persons::PersonStatus,
+    #[serde(with = "chrono::naive::serde::ts_milliseconds")]
+    assigned_date: NaiveDateTime,
+    area_name: Option<String>,
+}
+
+impl From<&persons::Person> for TimelineSnapshot {
+    fn from(person: &persons::Person) -> Self {
+        Self {
+            referral_status: person.referral_status,
+            person_status: person.person_status,
+            assigned_date: person.assigned_date,
+            area_name: person.area_name.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedTimeline {
+    /// Unix seconds
+    fetched: u64,
+    snapshot: TimelineSnapshot,
+    events: Vec<persons::TimelineEvent>,
+    #[serde(default)]
+    diagnostics: persons::ParseDiagnostics,
+}
+
+fn unix_now() -> Result<u64, ChurchError> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| std::io::Error::other("Your clock is wrong"))?
+        .as_secs())
+}
+
 /// Function to decode escape sequences including \xNN
-fn decode_escape_sequences(s: &str) -> anyhow::Result<String> {
+fn decode_escape_sequences(s: &str) -> String {
     // Replace URL encoded sequences
     let decoded_string = s
         .replace("\\x2D", "-") // Replace \x2D with '-'
@@ -435,5 +861,269 @@ fn decode_escape_sequences(s: &str) -> anyhow::Result<String> {
         .replace("\\x2F", "/") // Replace \x2F with '/'
         .replace("\\x3D", "="); // Replace \x3D with '='
 
-    Ok(decoded_string.to_string())
+    decoded_string.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::ChurchError,
+        mock::{self, Failure, MockServer, Route},
+    };
+
+    #[tokio::test]
+    async fn login() {
+        let server = MockServer::start().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        let token = client.login().await.unwrap();
+        assert_eq!(token.claims.mission_id, mock::MISSION_ID);
+        assert_eq!(server.logins(), 1);
+
+        // A new client picks up the saved token instead of logging in again
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        client.get_people_list().await.unwrap();
+        assert_eq!(server.logins(), 1);
+    }
+
+    #[tokio::test]
+    async fn corpus_contacts() {
+        let server = MockServer::with_corpus().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        let people = client.get_people_list().await.unwrap();
+
+        let mut results = Vec::new();
+        for (guid, _) in mock::corpus_timelines() {
+            let person = people.iter().find(|p| p.guid == guid).unwrap();
+            let last_contact = client.get_person_last_contact(person).await.unwrap();
+            let contact_time = client.get_person_contact_time(person).await.unwrap();
+            results.push((guid, last_contact.map(|t| t.to_string()), contact_time));
+        }
+        let expected = [
+            // Re-referred late at night and reached the next morning
+            ("c-better-green", Some("2024-03-06 07:00:00"), Some(30)),
+            // Referred before the day started and called that morning
+            ("c-green", Some("2024-03-02 10:00:00"), Some(165)),
+            ("c-null-zone", Some("2024-03-02 12:00:00"), Some(60)),
+            // Reached before the referral's working day started
+            ("c-progressing", Some("2024-03-01 23:00:00"), Some(0)),
+            // Never referred, so there's no contact time
+            ("c-unknown-person", Some("2024-03-03 14:00:00"), None),
+            ("c-yellow", None, None),
+        ]
+        .map(|(guid, last, time)| (guid.to_string(), last.map(str::to_string), time));
+        assert_eq!(results, expected);
+    }
+
+    #[tokio::test]
+    async fn bad_password() {
+        let server = MockServer::start().await;
+        let mut env = server.env();
+        env.church_password = "wrong".to_string();
+        let client = super::ChurchClient::new(env).await.unwrap();
+        let e = client.login().await.unwrap_err();
+        assert!(matches!(&e, ChurchError::Auth(m) if m.contains("Password is incorrect")));
+
+        // Bad credentials aren't retried
+        let e = client.get_people_list().await.unwrap_err();
+        assert!(matches!(e, ChurchError::Auth(_)));
+        assert_eq!(server.hits(Route::Answer), 2);
+        assert_eq!(server.logins(), 0);
+    }
+
+    #[tokio::test]
+    async fn totp_login() {
+        let secret = "JBSWY3DPEHPK3PXP";
+        let server = MockServer::start().await;
+        server.require_totp(secret);
+
+        let mut env = server.env();
+        env.church_totp_secret = Some("jbsw y3dp ehpk 3pxp".to_string());
+        let client = super::ChurchClient::new(env).await.unwrap();
+        client.login().await.unwrap();
+        assert_eq!(server.logins(), 1);
+        assert_eq!(server.hits(Route::Challenge), 1);
+        assert_eq!(server.hits(Route::Answer), 2);
+    }
+
+    #[tokio::test]
+    async fn people_list_relogs_on_expired_token() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        client.login().await.unwrap();
+
+        server.fail_next(Route::People, Failure::ExpiredToken);
+        let list = client.get_people_list().await.unwrap();
+        assert_eq!(list.len(), mock::fixture_people().len() - 1);
+        assert_eq!(server.logins(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_relogins_are_retried() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        client.login().await.unwrap();
+
+        server.fail_next(Route::People, Failure::ExpiredToken);
+        server.fail_next(Route::Auth, Failure::ServerError);
+        client.get_people_list().await.unwrap();
+        assert_eq!(server.logins(), 2);
+        assert_eq!(server.hits(Route::People), 2);
+        let stats = client.take_retry_stats();
+        assert_eq!(stats.retries, 2);
+        assert_eq!(stats.relogins, 1);
+
+        // A login that keeps failing counts against the tries like any other request
+        let max_tries = client.env.retry.max_tries;
+        server.fail_next(Route::People, Failure::ExpiredToken);
+        for _ in 0..max_tries {
+            server.fail_next(Route::Auth, Failure::ServerError);
+        }
+        let e = client.get_people_list().await.unwrap_err();
+        assert!(matches!(
+            e,
+            ChurchError::RetriesExhausted { attempts, .. } if attempts == max_tries
+        ));
+        assert!(matches!(e.root(), ChurchError::Status { status, .. } if status.is_server_error()));
+        assert_eq!(client.take_retry_stats().gave_up, 1);
+    }
+
+    #[tokio::test]
+    async fn expiring_token_relogs_before_request() {
+        let server = MockServer::with_fixtures().await;
+        server.set_token_lifetime(30);
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        client.login().await.unwrap();
+
+        server.set_token_lifetime(60 * 60);
+        client.get_people_list().await.unwrap();
+        assert_eq!(server.logins(), 2);
+        assert_eq!(server.hits(Route::People), 1);
+    }
+
+    #[tokio::test]
+    async fn people_list_gives_up() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        let max_tries = client.env.retry.max_tries;
+        for _ in 0..max_tries {
+            server.fail_next(Route::People, Failure::MalformedJson);
+        }
+        let e = client.get_people_list().await.unwrap_err();
+        assert!(matches!(
+            e,
+            ChurchError::RetriesExhausted { attempts, .. } if attempts == max_tries
+        ));
+        assert!(matches!(e.root(), ChurchError::Parse(_)));
+        assert_eq!(server.hits(Route::People), max_tries as usize);
+        assert_eq!(server.logins(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_dont_relog() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        server.fail_next(Route::People, Failure::RateLimited);
+        client.get_people_list().await.unwrap();
+        assert_eq!(server.hits(Route::People), 2);
+        assert_eq!(server.logins(), 1);
+    }
+
+    #[tokio::test]
+    async fn timeline_cache() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        let people = client.get_people_list().await.unwrap();
+        let mut ana = people.into_iter().find(|p| p.guid == "p-ana").unwrap();
+
+        client.get_cached_person_timeline(&ana).await.unwrap();
+        client.get_cached_person_timeline(&ana).await.unwrap();
+        assert_eq!(server.hits(Route::Timeline), 1);
+
+        // Someone recorded an attempt, so the old timeline is out of date
+        ana.referral_status = crate::persons::ReferralStatus::NotSuccessful;
+        client.get_cached_person_timeline(&ana).await.unwrap();
+        assert_eq!(server.hits(Route::Timeline), 2);
+
+        let mut env = server.env();
+        env.timeline_cache_hours = 0;
+        let client = super::ChurchClient::new(env).await.unwrap();
+        client.get_cached_person_timeline(&ana).await.unwrap();
+        assert_eq!(server.hits(Route::Timeline), 3);
+
+        // Huge lifetimes keep the cache forever instead of overflowing
+        let mut env = server.env();
+        env.timeline_cache_hours = u64::MAX;
+        let client = super::ChurchClient::new(env).await.unwrap();
+        client.get_cached_person_timeline(&ana).await.unwrap();
+        assert_eq!(server.hits(Route::Timeline), 3);
+
+        // Anything but a plain GUID never becomes a file name
+        ana.guid = "..p.ana".to_string();
+        client.get_cached_person_timeline(&ana).await.unwrap();
+        client.get_cached_person_timeline(&ana).await.unwrap();
+        assert_eq!(server.hits(Route::Timeline), 5);
+        let timelines = std::path::Path::new(&client.env.working_path).join("timelines");
+        assert!(std::fs::read_dir(timelines).unwrap().all(|f| !f
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .contains("..")));
+    }
+
+    #[tokio::test]
+    async fn timeline_retries_server_errors() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        client.login().await.unwrap();
+        let people = client.get_people_list().await.unwrap();
+        let dee = people.iter().find(|p| p.guid == "p-dee").unwrap();
+
+        server.fail_next(Route::Timeline, Failure::ServerError);
+        let timeline = client.get_person_timeline(dee).await.unwrap();
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(server.hits(Route::Timeline), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_are_counted() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        client.login().await.unwrap();
+
+        server.fail_next(Route::People, Failure::ExpiredToken);
+        server.fail_next(Route::People, Failure::RateLimited);
+        let people = client.get_people_list().await.unwrap();
+        let stats = client.take_retry_stats();
+        assert_eq!(stats.retries, 2);
+        assert_eq!(stats.relogins, 1);
+        assert_eq!(stats.rate_limited, 1);
+        assert_eq!(stats.gave_up, 0);
+        assert!(client.take_retry_stats().is_empty());
+
+        // Other client errors won't go away by asking again
+        let dee = people.iter().find(|p| p.guid == "p-dee").unwrap();
+        server.fail_next(Route::Timeline, Failure::NotFound);
+        let e = client.get_person_timeline(dee).await.unwrap_err();
+        assert!(matches!(e, ChurchError::Status { status, .. } if status == 404));
+        assert_eq!(server.hits(Route::Timeline), 1);
+        assert_eq!(client.take_retry_stats().gave_up, 1);
+    }
+
+    #[tokio::test]
+    async fn api_requests_share_auth_and_cookies() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+
+        // Both endpoints want the bearer token and the session cookie
+        let people = client.get_people_list().await.unwrap();
+        let dee = people.iter().find(|p| p.guid == "p-dee").unwrap();
+        client.get_person_timeline(dee).await.unwrap();
+        assert_eq!(server.logins(), 1);
+
+        // Refreshed cookies are saved once the work is done, not after every response
+        let path = std::path::Path::new(&client.env.working_path).join("cookies.json");
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("activity"));
+        client.save_changed_cookies().await.unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("activity"));
+    }
 }
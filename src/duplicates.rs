@@ -0,0 +1,262 @@
+// Jackson Coxson
+// Referral manager often has the same friend more than once, from different sources
+// or after they were referred again. This finds the copies.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::persons::{Person, ReferralStatus};
+
+/// What made two referrals look like the same friend
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MatchReason {
+    Phone,
+    Email,
+    NameAndArea,
+}
+
+impl std::fmt::Display for MatchReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Phone => write!(f, "same phone"),
+            Self::Email => write!(f, "same email"),
+            Self::NameAndArea => write!(f, "same name and area"),
+        }
+    }
+}
+
+/// Referrals that are probably the same friend
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    /// The copy that's kept when duplicates are collapsed, first
+    pub people: Vec<Person>,
+    pub reasons: BTreeSet<MatchReason>,
+}
+
+impl DuplicateGroup {
+    /// The group as a line for the referral secretary, without phone numbers or emails
+    pub fn report_line(&self) -> String {
+        let people = self
+            .people
+            .iter()
+            .map(|p| {
+                format!(
+                    "{} ({}, {})",
+                    p.full_name(),
+                    p.area_name.as_deref().unwrap_or("no area"),
+                    p.assigned_date.format("%Y-%m-%d")
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(" & ");
+        let reasons = self
+            .reasons
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        format!("{people} - {reasons}")
+    }
+}
+
+/// Finds groups of referrals that share a phone number, an email, or a name in the same area
+pub fn find_duplicates(people: &[Person]) -> Vec<DuplicateGroup> {
+    let mut parents = (0..people.len()).collect::<Vec<usize>>();
+    let mut reasons: HashMap<usize, BTreeSet<MatchReason>> = HashMap::new();
+    let mut seen: HashMap<(MatchReason, String), usize> = HashMap::new();
+
+    for (i, person) in people.iter().enumerate() {
+        for (reason, key) in match_keys(person) {
+            match seen.get(&(reason, key.clone())) {
+                Some(&first) => {
+                    let (a, b) = (find(&mut parents, first), find(&mut parents, i));
+                    parents[b] = a;
+                    reasons.entry(first).or_default().insert(reason);
+                    reasons.entry(i).or_default().insert(reason);
+                }
+                None => {
+                    seen.insert((reason, key), i);
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..people.len() {
+        let root = find(&mut parents, i);
+        groups.entry(root).or_default().push(i);
+    }
+    let mut groups = groups
+        .into_values()
+        .filter(|g| g.len() > 1)
+        .map(|g| {
+            let mut group = DuplicateGroup {
+                reasons: g.iter().flat_map(|i| reasons.remove(i)).flatten().collect(),
+                people: g.into_iter().map(|i| people[i].clone()).collect(),
+            };
+            group.people.sort_by_key(|p| (progress(p), p.assigned_date));
+            group
+        })
+        .collect::<Vec<DuplicateGroup>>();
+    groups.sort_by(|a, b| a.people[0].guid.cmp(&b.people[0].guid));
+    groups
+}
+
+/// Keeps the copy from each group of duplicates that got furthest, or else the oldest
+pub fn collapse(people: Vec<Person>) -> (Vec<Person>, Vec<DuplicateGroup>) {
+    let groups = find_duplicates(&people);
+    let dropped = groups
+        .iter()
+        .flat_map(|g| g.people.iter().skip(1))
+        .map(|p| p.guid.as_str())
+        .collect::<BTreeSet<&str>>();
+    let kept = people
+        .iter()
+        .filter(|p| !dropped.contains(p.guid.as_str()))
+        .cloned()
+        .collect();
+    (kept, groups)
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+/// Lower sorts first, so the copy that was reached comes before ones that weren't
+fn progress(person: &Person) -> u8 {
+    match person.referral_status {
+        ReferralStatus::Successful => 0,
+        ReferralStatus::NotSuccessful => 1,
+        _ => 2,
+    }
+}
+
+fn match_keys(person: &Person) -> Vec<(MatchReason, String)> {
+    let mut keys = Vec::new();
+    if let Some(phone) = &person.phone {
+        let digits = phone
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect::<String>();
+        // Country codes come and go, the last ten digits don't
+        if digits.len() >= 7 {
+            keys.push((
+                MatchReason::Phone,
+                digits[digits.len().saturating_sub(10)..].to_string(),
+            ));
+        }
+    }
+    if let Some(email) = &person.email {
+        let email = email.trim().to_lowercase();
+        if email.contains('@') {
+            keys.push((MatchReason::Email, email));
+        }
+    }
+    // A first name alone is too common to mean the same person
+    let has_last_name = person
+        .last_name
+        .as_deref()
+        .is_some_and(|l| !l.trim().is_empty());
+    if let Some(area) = person.area_name.as_ref().filter(|_| has_last_name) {
+        let name = person
+            .full_name()
+            .to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+        // Area names can repeat across zones, so the zone is part of the area
+        let zone = person.zone_id.map(|z| z.to_string()).unwrap_or_default();
+        keys.push((
+            MatchReason::NameAndArea,
+            format!("{name}|{zone}|{}", area.trim().to_lowercase()),
+        ));
+    }
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MatchReason;
+    use crate::mock::{people, person, with, NORTH, SOUTH};
+    use serde_json::json;
+
+    #[test]
+    fn duplicates() {
+        let people = people(&[
+            with(
+                person("p-1", "Ana", 10, 1, Some(NORTH), Some("Alpha"), 10),
+                json!({"lastName": "Lopez", "phone": "+1 (555) 010-0100"}),
+            ),
+            with(
+                person("p-2", "ana", 30, 2, Some(NORTH), Some("Alpha"), 90),
+                json!({"lastName": "LOPEZ"}),
+            ),
+            with(
+                person("p-3", "Annie", 10, 1, Some(SOUTH), Some("Bravo"), 5),
+                json!({"phone": "555 010 0100", "email": "a@x.org"}),
+            ),
+            with(
+                person("p-4", "Ben", 10, 1, Some(NORTH), Some("Alpha"), 5),
+                json!({"email": " A@X.org"}),
+            ),
+            // Same first name in another area isn't enough
+            person("p-5", "Ana", 10, 1, Some(SOUTH), Some("Bravo"), 5),
+            with(
+                person("p-6", "Cy", 10, 1, None, None, 5),
+                json!({"phone": "n/a"}),
+            ),
+            with(
+                person("p-7", "Cy", 10, 1, None, None, 5),
+                json!({"phone": "n/a"}),
+            ),
+            // Nor is a first name alone in the same area
+            person("p-8", "Maria", 10, 1, Some(NORTH), Some("Alpha"), 5),
+            with(
+                person("p-9", "Maria", 10, 1, Some(NORTH), Some("Alpha"), 5),
+                json!({"lastName": " "}),
+            ),
+            // Nor the same name in areas that share a name in different zones
+            with(
+                person("p-10", "Hal", 10, 1, Some(NORTH), Some("Alpha"), 5),
+                json!({"lastName": "Kim"}),
+            ),
+            with(
+                person("p-11", "Hal", 10, 1, Some(SOUTH), Some("Alpha"), 5),
+                json!({"lastName": "Kim"}),
+            ),
+        ]);
+
+        let groups = super::find_duplicates(&people);
+        assert_eq!(groups.len(), 1);
+        let group = &groups[0];
+        // The one that was reached comes first
+        let guids = group
+            .people
+            .iter()
+            .map(|p| p.guid.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(guids, ["p-2", "p-1", "p-3", "p-4"]);
+        assert_eq!(
+            group.reasons.iter().copied().collect::<Vec<_>>(),
+            [
+                MatchReason::Phone,
+                MatchReason::Email,
+                MatchReason::NameAndArea
+            ]
+        );
+        let line = group.report_line();
+        assert!(line.starts_with("ana LOPEZ (Alpha, "));
+        assert!(!line.contains("555") && !line.contains("a@x.org"));
+
+        let (kept, _) = super::collapse(people);
+        let guids = kept.iter().map(|p| p.guid.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            guids,
+            ["p-2", "p-5", "p-6", "p-7", "p-8", "p-9", "p-10", "p-11"]
+        );
+    }
+}
@@ -10,24 +10,96 @@ use std::{
 use dialoguer::{theme::ColorfulTheme, Input, Password, Select};
 use log::error;
 
+use crate::{
+    cassette::CassetteMode,
+    filter::Filter,
+    org::Level,
+    policy::{self, DEFAULT_POLICY},
+    report::ReportOrder,
+    retry::RetryPolicy,
+};
+
+pub const REFERRAL_MANAGER_URL: &str = "https://referralmanager.churchofjesuschrist.org";
+pub const CHURCH_ID_URL: &str = "https://id.churchofjesuschrist.org";
+pub const DEFAULT_CONCURRENCY: usize = 8;
+pub const DEFAULT_TIMELINE_CACHE_HOURS: u64 = 6;
+
 #[derive(Clone, Debug)]
 pub struct Env {
     pub church_username: String,
     pub church_password: String,
+    /// The base32 secret behind an authenticator app, so MFA logins can happen unattended
+    pub church_totp_secret: Option<String>,
     pub working_path: String,
+    pub endpoints: Endpoints,
+    /// How many requests to have in flight at once when fetching timelines
+    pub concurrency: usize,
+    /// How long a cached timeline is trusted if the person hasn't changed. 0 disables the cache.
+    pub timeline_cache_hours: u64,
+    /// How failed requests to church servers are retried
+    pub retry: RetryPolicy,
+    /// Whether to record church server responses to the working path, or replay them
+    pub cassette: CassetteMode,
+    /// Whether suspected duplicate referrals count once in reports and averages
+    pub collapse_duplicates: bool,
+    /// Narrows who reports list, on top of the policy
+    pub report_filter: Filter,
+    /// Narrows who average contact times look at, on top of the policy
+    pub average_filter: Filter,
+    /// The name of the policy for who is uncontacted, see [crate::policy::Policy]
+    pub policy: String,
+    /// What average contact times are grouped by
+    pub average_by: Level,
+    /// What reports are sorted by
+    pub report_order: ReportOrder,
+}
+
+/// The base URLs the church client talks to.
+/// These only need changing to point the program at a local stand-in server.
+#[derive(Clone, Debug)]
+pub struct Endpoints {
+    /// Referral manager, which serves the login page and the `/services` API
+    pub referral_manager: String,
+    /// The Okta identity server, which serves the `/idp/idx` login flow
+    pub church_id: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Self {
+            referral_manager: REFERRAL_MANAGER_URL.to_string(),
+            church_id: CHURCH_ID_URL.to_string(),
+        }
+    }
+}
+
+impl Endpoints {
+    /// Reads the base URLs from ``REFERRAL_MANAGER_URL`` and ``CHURCH_ID_URL``,
+    /// falling back to the real church servers
+    pub fn from_vars() -> Self {
+        let default = Self::default();
+        Self {
+            referral_manager: std::env::var("REFERRAL_MANAGER_URL")
+                .map(|u| u.trim_end_matches('/').to_string())
+                .unwrap_or(default.referral_manager),
+            church_id: std::env::var("CHURCH_ID_URL")
+                .map(|u| u.trim_end_matches('/').to_string())
+                .unwrap_or(default.church_id),
+        }
+    }
 }
 
 /// Checks the environment variables to make sure we are good to go.
-/// Returns true on a successful startup
+/// Returns an error if one is set to something we can't use
 ///
 /// # Safety
 /// Call this before calling async code.
 /// Apparently we haven't, as society, figured out how to make
 /// reading and writing env vars thread safe.
-pub fn check_vars() -> Env {
+pub fn check_vars() -> anyhow::Result<Env> {
     dotenvy::dotenv().ok();
 
-    Env {
+    Ok(Env {
         church_username: std::env::var("CHURCH_USERNAME").unwrap_or_else(|_| {
             let password: String = Input::with_theme(&ColorfulTheme::default())
                 .with_prompt("Enter your churchofjesuschrist.org username")
@@ -46,6 +118,9 @@ pub fn check_vars() -> Env {
             save_var("CHURCH_PASSWORD", &password);
             password
         }),
+        church_totp_secret: std::env::var("CHURCH_TOTP_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty()),
         working_path: std::env::var("WORKING_PATH").unwrap_or_else(|_| {
             let here = std::env::current_dir().unwrap().join("rm_working_path");
             if std::fs::create_dir_all(&here).is_err() {
@@ -60,7 +135,32 @@ pub fn check_vars() -> Env {
             save_var("WORKING_PATH", &password);
             password
         }),
-    }
+        endpoints: Endpoints::from_vars(),
+        concurrency: std::env::var("CONCURRENT_REQUESTS")
+            .ok()
+            .and_then(|c| c.parse::<usize>().ok())
+            .filter(|c| *c > 0)
+            .unwrap_or(DEFAULT_CONCURRENCY),
+        timeline_cache_hours: std::env::var("TIMELINE_CACHE_HOURS")
+            .ok()
+            .and_then(|c| c.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_TIMELINE_CACHE_HOURS),
+        retry: RetryPolicy::from_vars(),
+        cassette: CassetteMode::from_vars()?,
+        collapse_duplicates: std::env::var("COLLAPSE_DUPLICATES")
+            .is_ok_and(|c| matches!(c.to_lowercase().as_str(), "1" | "true" | "yes")),
+        report_filter: Filter::from_var("REPORT_FILTER", "")?,
+        average_filter: Filter::from_var("AVERAGE_FILTER", "")?,
+        policy: match std::env::var("POLICY") {
+            Ok(name) => {
+                policy::check_name(&name).map_err(|e| anyhow::anyhow!("POLICY isn't valid: {e}"))?;
+                name
+            }
+            Err(_) => DEFAULT_POLICY.to_string(),
+        },
+        average_by: Level::from_vars()?,
+        report_order: ReportOrder::from_vars()?,
+    })
 }
 
 fn save_var(key: &str, val: &str) {
@@ -0,0 +1,149 @@
+// Jackson Coxson
+// Errors from talking to church servers
+
+use std::time::Duration;
+
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+
+use crate::cassette::redact_url;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChurchError {
+    /// The servers turned down the credentials or verification code.
+    /// Trying again won't help until someone fixes them.
+    #[error("Login failed: {0}")]
+    Auth(String),
+
+    /// The server answered with an error status
+    #[error("{url} returned {status}")]
+    Status { status: StatusCode, url: String },
+
+    /// The server wants us to slow down
+    #[error("Rate limited by {url}")]
+    RateLimited {
+        url: String,
+        retry_after: Option<Duration>,
+    },
+
+    /// The request never got an answer
+    #[error("Network error: {0}")]
+    Network(reqwest::Error),
+
+    /// The response wasn't shaped like we expected, most likely because the API changed.
+    /// It's tried again in case the response was cut off, but never logged in again for.
+    #[error("Unable to parse {0}")]
+    Parse(String),
+
+    #[error("Gave up after {attempts} tries: {last}")]
+    RetriesExhausted {
+        attempts: u8,
+        last: Box<ChurchError>,
+    },
+
+    /// Replaying a cassette that never saw this request
+    #[error("No recorded response for {0}")]
+    NotRecorded(String),
+
+    /// Reading or writing the working path failed
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl From<reqwest::Error> for ChurchError {
+    fn from(e: reqwest::Error) -> Self {
+        // URLs can carry state tokens, so they're redacted before anything is logged
+        if e.is_decode() {
+            return Self::Parse(e.without_url().to_string());
+        }
+        match (e.status(), e.url()) {
+            (Some(status), Some(url)) => Self::Status {
+                status,
+                url: redact_url(url),
+            },
+            _ => Self::Network(e.without_url()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ChurchError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Parse(e.to_string())
+    }
+}
+
+impl From<dialoguer::Error> for ChurchError {
+    fn from(e: dialoguer::Error) -> Self {
+        match e {
+            dialoguer::Error::IO(e) => Self::Io(e),
+        }
+    }
+}
+
+impl ChurchError {
+    /// The error that actually happened, looking past any retries
+    pub fn root(&self) -> &Self {
+        match self {
+            Self::RetriesExhausted { last, .. } => last.root(),
+            e => e,
+        }
+    }
+
+    /// Whether logging in again could fix this
+    pub fn needs_login(&self) -> bool {
+        matches!(
+            self.root(),
+            Self::Status { status, .. }
+                if *status == StatusCode::UNAUTHORIZED || *status == StatusCode::FORBIDDEN
+        )
+    }
+
+    /// Whether the same request could work if tried again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Status { status, .. } => {
+                self.needs_login()
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || status.is_server_error()
+            }
+            Self::RateLimited { .. } | Self::Network(_) | Self::Parse(_) => true,
+            Self::Auth(_) | Self::RetriesExhausted { .. } | Self::NotRecorded(_) | Self::Io(_) => {
+                false
+            }
+        }
+    }
+
+    /// How long the server asked us to wait, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self.root() {
+            Self::RateLimited { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// The error for a response's status, if it's an error status
+pub fn status_error(res: &Response) -> Option<ChurchError> {
+    let status = res.status();
+    let url = redact_url(res.url());
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        return Some(ChurchError::RateLimited { url, retry_after });
+    }
+    if status.is_client_error() || status.is_server_error() {
+        return Some(ChurchError::Status { status, url });
+    }
+    None
+}
+
+/// Turns error statuses into errors, passing good responses through
+pub fn check_status(res: Response) -> Result<Response, ChurchError> {
+    match status_error(&res) {
+        Some(e) => Err(e),
+        None => Ok(res),
+    }
+}
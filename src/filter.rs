@@ -0,0 +1,496 @@
+// Jackson Coxson
+// A small language for picking which people a report or average looks at, like
+// zone="North" and status<NewMember and assigned>48h
+
+use chrono::{Duration, NaiveDateTime};
+
+use crate::persons::{Person, PersonStatus, ReferralStatus};
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{message} at character {position}")]
+pub struct FilterError {
+    pub message: String,
+    pub position: usize,
+}
+
+/// A parsed filter. An empty one lets everyone through.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    source: String,
+    expr: Option<Expr>,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Comparison),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn test<T: PartialOrd>(self, a: T, b: T) -> bool {
+        match self {
+            Self::Eq => a == b,
+            Self::Ne => a != b,
+            Self::Lt => a < b,
+            Self::Le => a <= b,
+            Self::Gt => a > b,
+            Self::Ge => a >= b,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum TextField {
+    Zone,
+    Area,
+    Name,
+    Language,
+    Source,
+    Gender,
+}
+
+#[derive(Clone, Debug)]
+enum Comparison {
+    /// Compared ignoring case. None is written ``none`` and matches a missing value.
+    Text(TextField, Op, Option<String>),
+    ZoneId(Op, usize),
+    District(Op, usize),
+    Status(Op, PersonStatus),
+    /// A person status this version doesn't know, written ``status=unknown``
+    UnknownStatus(Op),
+    Referral(Op, ReferralStatus),
+    /// How long ago the person was assigned
+    Assigned(Op, Duration),
+}
+
+impl Comparison {
+    fn matches(&self, person: &Person, now: NaiveDateTime) -> bool {
+        match self {
+            Self::Text(field, op, value) => {
+                let actual = match field {
+                    TextField::Zone => person.zone_name.clone(),
+                    TextField::Area => person.area_name.clone(),
+                    TextField::Name => Some(person.full_name()),
+                    TextField::Language => person.preferred_language.clone(),
+                    TextField::Source => person.referral_source.clone(),
+                    TextField::Gender => person.gender.map(|g| g.to_string()),
+                };
+                let equal = match (actual, value) {
+                    (Some(a), Some(v)) => a.trim().eq_ignore_ascii_case(v),
+                    (None, None) => true,
+                    _ => false,
+                };
+                equal == (*op == Op::Eq)
+            }
+            Self::ZoneId(op, id) => person.zone_id.is_some_and(|z| op.test(z, *id)),
+            Self::District(op, id) => person.district_id.is_some_and(|d| op.test(d, *id)),
+            Self::Status(op, status) => op.test(person.person_status, *status),
+            Self::UnknownStatus(op) => {
+                matches!(person.person_status, PersonStatus::Unknown(_)) == (*op == Op::Eq)
+            }
+            Self::Referral(op, status) => (person.referral_status == *status) == (*op == Op::Eq),
+            Self::Assigned(op, age) => {
+                op.test(now.signed_duration_since(person.assigned_date), *age)
+            }
+        }
+    }
+}
+
+impl Filter {
+    /// Reads a filter from an env var, or `default` if it's unset
+    pub fn from_var(key: &str, default: &str) -> anyhow::Result<Self> {
+        let source = std::env::var(key).unwrap_or_else(|_| default.to_string());
+        source
+            .parse()
+            .map_err(|e| anyhow::anyhow!("{key} isn't a valid filter: {e}"))
+    }
+
+    pub fn matches(&self, person: &Person, now: NaiveDateTime) -> bool {
+        self.expr.as_ref().is_none_or(|e| e.matches(person, now))
+    }
+}
+
+impl Expr {
+    fn matches(&self, person: &Person, now: NaiveDateTime) -> bool {
+        match self {
+            Self::And(a, b) => a.matches(person, now) && b.matches(person, now),
+            Self::Or(a, b) => a.matches(person, now) || b.matches(person, now),
+            Self::Not(e) => !e.matches(person, now),
+            Self::Compare(c) => c.matches(person, now),
+        }
+    }
+}
+
+impl std::str::FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse(s).map_err(|e| FilterError {
+            // Tokens are found by byte, but people count characters
+            position: s[..e.position].chars().count(),
+            ..e
+        })
+    }
+}
+
+fn parse(s: &str) -> Result<Filter, FilterError> {
+    let mut parser = Parser {
+        tokens: tokenize(s)?,
+        at: 0,
+        end: s.len(),
+    };
+    let expr = if parser.tokens.is_empty() {
+        None
+    } else {
+        let expr = parser.or()?;
+        if let Some((position, token)) = parser.tokens.get(parser.at) {
+            return Err(error(format!("expected and/or, found {token}"), *position));
+        }
+        Some(expr)
+    };
+    Ok(Filter {
+        source: s.trim().to_string(),
+        expr,
+    })
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.expr {
+            Some(_) => write!(f, "{}", self.source),
+            None => write!(f, "everyone"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Word(w) => write!(f, "{w}"),
+            Self::Quoted(q) => write!(f, "\"{q}\""),
+            Self::Op(op) => write!(
+                f,
+                "{}",
+                match op {
+                    Op::Eq => "=",
+                    Op::Ne => "!=",
+                    Op::Lt => "<",
+                    Op::Le => "<=",
+                    Op::Gt => ">",
+                    Op::Ge => ">=",
+                }
+            ),
+            Self::Open => write!(f, "("),
+            Self::Close => write!(f, ")"),
+        }
+    }
+}
+
+fn error(message: String, position: usize) -> FilterError {
+    FilterError { message, position }
+}
+
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '"' => {
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => quoted.push(c),
+                        None => return Err(error("unclosed quote".to_string(), i)),
+                    }
+                }
+                Token::Quoted(quoted)
+            }
+            '=' => Token::Op(Op::Eq),
+            '!' | '<' | '>' => {
+                let equals = chars.next_if(|(_, c)| *c == '=').is_some();
+                Token::Op(match (c, equals) {
+                    ('!', true) => Op::Ne,
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    _ => return Err(error("expected = after !".to_string(), i)),
+                })
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some((_, c)) =
+                    chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_' || *c == '-')
+                {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            c => return Err(error(format!("unexpected {c}"), i)),
+        };
+        tokens.push((i, token));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    at: usize,
+    /// Where errors at the end of the filter point
+    end: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<(usize, Token), FilterError> {
+        let token = self
+            .tokens
+            .get(self.at)
+            .cloned()
+            .ok_or_else(|| error("the filter ends early".to_string(), self.end))?;
+        self.at += 1;
+        Ok(token)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.tokens.get(self.at) {
+            Some((_, Token::Word(w))) if w.eq_ignore_ascii_case(keyword) => {
+                self.at += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.unary()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, FilterError> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self
+            .tokens
+            .get(self.at)
+            .is_some_and(|(_, t)| *t == Token::Open)
+        {
+            self.at += 1;
+            let expr = self.or()?;
+            return match self.next()? {
+                (_, Token::Close) => Ok(expr),
+                (i, t) => Err(error(format!("expected ), found {t}"), i)),
+            };
+        }
+        self.comparison().map(Expr::Compare)
+    }
+
+    fn comparison(&mut self) -> Result<Comparison, FilterError> {
+        let (field_at, field) = match self.next()? {
+            (i, Token::Word(w)) => (i, w.to_lowercase()),
+            (i, t) => return Err(error(format!("expected a field, found {t}"), i)),
+        };
+        let op = match self.next()? {
+            (_, Token::Op(op)) => op,
+            (i, t) => return Err(error(format!("expected a comparison, found {t}"), i)),
+        };
+        let (value_at, value, quoted) = match self.next()? {
+            (i, Token::Word(w)) => (i, w, false),
+            (i, Token::Quoted(q)) => (i, q, true),
+            (i, t) => return Err(error(format!("expected a value, found {t}"), i)),
+        };
+        let equality_only = |op: Op| {
+            if matches!(op, Op::Eq | Op::Ne) {
+                Ok(op)
+            } else {
+                Err(error(format!("{field} can only use = or !="), field_at))
+            }
+        };
+        let number = |v: &str| {
+            v.parse::<usize>()
+                .map_err(|_| error(format!("expected a number, found {v}"), value_at))
+        };
+        let text = |field| {
+            let value = (quoted || !value.eq_ignore_ascii_case("none")).then(|| value.clone());
+            Ok(Comparison::Text(field, equality_only(op)?, value))
+        };
+
+        match field.as_str() {
+            "zone" if !quoted && value.chars().all(|c| c.is_ascii_digit()) => {
+                Ok(Comparison::ZoneId(op, number(&value)?))
+            }
+            "zone" => text(TextField::Zone),
+            "area" => text(TextField::Area),
+            "name" => text(TextField::Name),
+            "language" => text(TextField::Language),
+            "source" => text(TextField::Source),
+            "gender" => {
+                let value = match value.to_lowercase().as_str() {
+                    "m" => "Male".to_string(),
+                    "f" => "Female".to_string(),
+                    _ => value.clone(),
+                };
+                let value = (!value.eq_ignore_ascii_case("none")).then_some(value);
+                Ok(Comparison::Text(
+                    TextField::Gender,
+                    equality_only(op)?,
+                    value,
+                ))
+            }
+            "district" => Ok(Comparison::District(op, number(&value)?)),
+            "status" if !quoted && value.eq_ignore_ascii_case("unknown") => {
+                Ok(Comparison::UnknownStatus(equality_only(op)?))
+            }
+            "status" => Ok(Comparison::Status(
+                op,
+                value.parse().map_err(|e| error(e, value_at))?,
+            )),
+            "referral" => Ok(Comparison::Referral(
+                equality_only(op)?,
+                value.parse().map_err(|e| error(e, value_at))?,
+            )),
+            "assigned" => Ok(Comparison::Assigned(op, duration(&value, value_at)?)),
+            _ => Err(error(format!("unknown field {field}"), field_at)),
+        }
+    }
+}
+
+/// A duration like ``30m``, ``48h``, ``3d`` or ``2w``
+fn duration(value: &str, position: usize) -> Result<Duration, FilterError> {
+    let bad = || {
+        error(
+            format!("expected a duration like 48h or 3d, found {value}"),
+            position,
+        )
+    };
+    let unit = value.chars().last().ok_or_else(bad)?;
+    let amount = value[..value.len() - unit.len_utf8()]
+        .parse::<i64>()
+        .map_err(|_| bad())?;
+    let duration = match unit.to_ascii_lowercase() {
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        'w' => Duration::try_weeks(amount),
+        _ => return Err(bad()),
+    };
+    duration.ok_or_else(|| error(format!("{value} is too long"), position))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use crate::mock::{people, person, with, NORTH, SOUTH};
+
+    #[test]
+    fn filters() {
+        let people = people(&[
+            with(
+                person("p-1", "Ana", 10, 1, Some(NORTH), Some("Alpha"), 72),
+                serde_json::json!({"gender": "F", "preferredLanguage": "Spanish"}),
+            ),
+            person("p-2", "Ben", 30, 2, Some(NORTH), Some("Alpha"), 10),
+            person("p-3", "Cy", 20, 6, Some(SOUTH), Some("Bravo Two"), 100),
+            person("p-4", "Di", 10, 1, None, None, 1),
+            person("p-5", "Jo", 20, 99, Some(SOUTH), Some("Bravo"), 80),
+        ]);
+        let now = chrono::Utc::now().naive_utc();
+        let picked = |filter: &str| {
+            let filter = filter.parse::<Filter>().unwrap();
+            people
+                .iter()
+                .filter(|p| filter.matches(p, now))
+                .map(|p| p.first_name.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(picked(""), ["Ana", "Ben", "Cy", "Di", "Jo"]);
+        assert_eq!(
+            picked(r#"zone="North" and status<NewMember and assigned>48h"#),
+            ["Ana"]
+        );
+        assert_eq!(picked("zone=1 or zone=none"), ["Ana", "Ben", "Di"]);
+        assert_eq!(picked(r#"area="bravo two""#), ["Cy"]);
+        assert_eq!(picked("not (referral=NotAttempted or status>=6)"), ["Ben"]);
+        assert_eq!(picked("gender=f and language=spanish"), ["Ana"]);
+        assert_eq!(picked("status=2 or referral=20"), ["Ben", "Cy", "Jo"]);
+        assert_eq!(picked("status=unknown"), ["Jo"]);
+        assert_eq!(picked("status!=unknown and referral=20"), ["Cy"]);
+        assert_eq!(picked("assigned<=2d and district=10"), ["Ben"]);
+
+        // The default policy picks the same people the old hardcoded rules did
+        let policy = crate::policy::Policy::default();
+        // and keeps people whose status it doesn't know
+        assert_eq!(picked(&policy.report_rule()), ["Ana", "Di", "Jo"]);
+        assert_eq!(picked(&policy.average_rule()), ["Ben"]);
+
+        let err = |filter: &str| filter.parse::<Filter>().unwrap_err().to_string();
+        assert_eq!(
+            err("zone=North and"),
+            "the filter ends early at character 14"
+        );
+        assert_eq!(err("colour=red"), "unknown field colour at character 0");
+        assert_eq!(
+            err("zone<North"),
+            "zone can only use = or != at character 0"
+        );
+        assert_eq!(
+            err("assigned>soon"),
+            "expected a duration like 48h or 3d, found soon at character 9"
+        );
+        assert_eq!(
+            err("status=Blue"),
+            "unknown person status Blue at character 7"
+        );
+        assert_eq!(
+            err("status<unknown"),
+            "status can only use = or != at character 0"
+        );
+        assert_eq!(err("(zone=1"), "the filter ends early at character 7");
+        assert_eq!(
+            err("zone=1 zone=2"),
+            "expected and/or, found zone at character 7"
+        );
+        assert_eq!(
+            err("assigned>99999999999999w"),
+            "99999999999999w is too long at character 9"
+        );
+        assert_eq!(err(r#"area="Zoë" %"#), "unexpected % at character 11");
+    }
+}
@@ -0,0 +1,250 @@
+// Jackson Coxson
+// Reports saved on earlier days, and what changed from one to the next
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use chrono::NaiveDate;
+
+use crate::{
+    env::Env,
+    policy::DEFAULT_POLICY,
+    report::{Report, ReportEntry},
+};
+
+pub const REPORTS_DIR: &str = "reports";
+
+/// Where a day's report is saved, beside the default policy's one for any other policy
+pub fn file_name(date: NaiveDate, policy: &str) -> String {
+    if policy == DEFAULT_POLICY {
+        format!("{}.json", date.format("%Y-%m-%d"))
+    } else {
+        format!("{}.{policy}.json", date.format("%Y-%m-%d"))
+    }
+}
+
+/// Every day with a report saved under the policy in use, oldest first
+pub fn days(env: &Env) -> anyhow::Result<Vec<NaiveDate>> {
+    let dir = PathBuf::from(&env.working_path).join(REPORTS_DIR);
+    std::fs::create_dir_all(&dir)?;
+
+    let mut days = Vec::new();
+    for f in std::fs::read_dir(&dir)? {
+        let f = f?;
+        if !f.file_type()?.is_file() {
+            continue;
+        }
+        let path = f.path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        if let Some(day) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| match env.policy.as_str() {
+                DEFAULT_POLICY => Some(s),
+                policy => s.strip_suffix(&format!(".{policy}")),
+            })
+            .and_then(|s| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok())
+        {
+            days.push(day);
+        }
+    }
+    days.sort();
+    Ok(days)
+}
+
+/// Reads a date given on the command line
+pub fn parse_day(s: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| anyhow::anyhow!("{s} isn't a date, use YYYY-MM-DD"))
+}
+
+/// What changed between the reports of two days
+#[derive(Clone, Debug)]
+pub struct ReportDiff {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// On the later report but not the earlier one
+    pub added: Vec<ReportEntry>,
+    /// On the earlier report but gone from the later one
+    pub resolved: Vec<ReportEntry>,
+    /// On both, with how many days in a row they've been on up to the later one
+    pub remaining: Vec<(ReportEntry, usize)>,
+}
+
+impl ReportDiff {
+    /// Compares two reports, counting everyone remaining as on for just the later one
+    fn between(from: NaiveDate, old: &Report, to: NaiveDate, new: &Report) -> Self {
+        let old_keys = old
+            .entries
+            .iter()
+            .map(|e| e.key())
+            .collect::<HashSet<String>>();
+        let new_keys = new
+            .entries
+            .iter()
+            .map(|e| e.key())
+            .collect::<HashSet<String>>();
+
+        let mut diff = Self {
+            from,
+            to,
+            added: Vec::new(),
+            resolved: Vec::new(),
+            remaining: Vec::new(),
+        };
+        for entry in &new.entries {
+            if old_keys.contains(&entry.key()) {
+                diff.remaining.push((entry.clone(), 1));
+            } else {
+                diff.added.push(entry.clone());
+            }
+        }
+        diff.resolved = old
+            .entries
+            .iter()
+            .filter(|e| !new_keys.contains(&e.key()))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    /// Compares the saved reports of two days, counting how many days in a row everyone's been on
+    pub fn load(env: &Env, from: NaiveDate, to: NaiveDate) -> anyhow::Result<Self> {
+        let read = |day: NaiveDate| {
+            Report::read_report_on(env, day, &env.policy)?
+                .ok_or_else(|| anyhow::anyhow!("There's no report saved for {day}"))
+        };
+        let mut diff = Self::between(from, &read(from)?, to, &read(to)?);
+
+        let mut running = diff
+            .remaining
+            .iter()
+            .map(|(e, count)| (e.key(), *count))
+            .collect::<HashMap<String, usize>>();
+        let mut counts = HashMap::new();
+        let mut expected = to;
+        for day in days(env)?.into_iter().rev().filter(|d| *d < to) {
+            expected = expected.pred_opt().unwrap_or(expected);
+            // A day without a saved report ends the count
+            if running.is_empty() || day != expected {
+                break;
+            }
+            let keys = read(day)?
+                .entries
+                .iter()
+                .map(|e| e.key())
+                .collect::<HashSet<String>>();
+            running.retain(|key, count| {
+                if keys.contains(key) {
+                    *count += 1;
+                    true
+                } else {
+                    counts.insert(key.clone(), *count);
+                    false
+                }
+            });
+        }
+        counts.extend(running);
+
+        for (entry, count) in &mut diff.remaining {
+            *count = counts[&entry.key()];
+        }
+        // Longest on the list first, otherwise in report order
+        diff.remaining
+            .sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        Ok(diff)
+    }
+}
+
+impl std::fmt::Display for ReportDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Changes from {} to {}", self.from, self.to)?;
+        write!(f, "\nNewly added ({})", self.added.len())?;
+        for e in &self.added {
+            write!(f, "\n  - {}", e.line())?;
+        }
+        write!(f, "\nResolved ({})", self.resolved.len())?;
+        for e in &self.resolved {
+            write!(f, "\n  - {}", e.line())?;
+        }
+        write!(f, "\nStill on the list ({})", self.remaining.len())?;
+        for (e, count) in &self.remaining {
+            let days = if *count == 1 { "day" } else { "days" };
+            write!(f, "\n  - {} - {count} {days} in a row", e.line())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::mock::{self, people, person, NORTH};
+
+    #[tokio::test]
+    async fn history() {
+        let server = mock::MockServer::with_fixtures().await;
+        let env = server.env();
+        let dir = std::path::PathBuf::from(&env.working_path).join(super::REPORTS_DIR);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let save = |date: &str, guids: &[&str]| {
+            let list = guids
+                .iter()
+                .map(|g| person(g, &g.to_uppercase(), 10, 1, Some(NORTH), Some("Alpha"), 50))
+                .collect::<Vec<_>>();
+            let mut report = crate::report::Report::new();
+            for p in people(&list) {
+                report.add_person(p, None);
+            }
+            let file = std::fs::File::create(dir.join(format!("{date}.json"))).unwrap();
+            serde_json::to_writer(file, &report).unwrap();
+        };
+        save("2026-10-01", &["a", "b", "c"]);
+        save("2026-10-02", &["a", "c"]);
+        // Nothing saved on the 3rd
+        save("2026-10-04", &["a", "c", "d"]);
+        save("2026-10-05", &["a", "d", "e"]);
+        std::fs::write(dir.join("notes.txt"), "not a report").unwrap();
+
+        let days = super::days(&env).unwrap();
+        assert_eq!(days.len(), 4);
+        assert_eq!(days[0], NaiveDate::from_ymd_opt(2026, 10, 1).unwrap());
+
+        let day = |s: &str| super::parse_day(s).unwrap();
+        let diff = super::ReportDiff::load(&env, day("2026-10-04"), day("2026-10-05")).unwrap();
+        let names = |entries: &[crate::report::ReportEntry]| {
+            entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&diff.added), ["E"]);
+        assert_eq!(names(&diff.resolved), ["C"]);
+        let remaining = diff
+            .remaining
+            .iter()
+            .map(|(e, n)| (e.name.as_str(), *n))
+            .collect::<Vec<_>>();
+        // Nothing was saved on the 3rd, so A's run starts on the 4th
+        assert_eq!(remaining, [("A", 2), ("D", 2)]);
+        assert!(diff
+            .to_string()
+            .contains("Still on the list (2)\n  - A (no phone or email) - 2 days in a row"));
+        let diff = super::ReportDiff::load(&env, day("2026-10-02"), day("2026-10-04")).unwrap();
+        assert!(diff.to_string().contains("- 1 day in a row"));
+        // Dates further apart count the same way as the days next to each other
+        let diff = super::ReportDiff::load(&env, day("2026-10-01"), day("2026-10-05")).unwrap();
+        assert_eq!(diff.remaining.len(), 1);
+        assert_eq!(diff.remaining[0].1, 2);
+
+        save("2026-10-03", &["a"]);
+        let diff = super::ReportDiff::load(&env, day("2026-10-04"), day("2026-10-05")).unwrap();
+        assert_eq!(diff.remaining[0].1, 5);
+
+        assert!(super::ReportDiff::load(&env, day("2026-10-06"), day("2026-10-05")).is_err());
+        assert!(super::parse_day("yesterday").is_err());
+    }
+}
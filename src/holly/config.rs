@@ -1,28 +1,32 @@
 // Jackson Coxson
 
-use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
-    str::FromStr,
-};
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
 
-use dialoguer::{theme::ColorfulTheme, Input};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
 use serde::{Deserialize, Serialize};
 
+use crate::org::{Level, Mission, OrgUnit};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub last_transfer_start: i64,
     pub zone_chats: HashMap<usize, String>,
     pub unassigned_chat: Option<String>,
+    /// Chats for districts, areas or the whole mission, on top of the zone chats.
+    /// Only district chats are asked about in the settings, the rest are set in the file.
+    #[serde(default)]
+    pub unit_chats: HashMap<OrgUnit, String>,
     pub holly_socket: String,
     pub name: String,
+    /// The policy for who is uncontacted in Holly's messages, instead of ``POLICY``
+    #[serde(default)]
+    pub policy: Option<String>,
+    /// Zones left out of the average contact times, by name
     pub blacklist: Option<Vec<String>>,
 }
 
 impl Config {
-    pub async fn force_load(
-        church_client: &mut crate::church::ChurchClient,
-    ) -> anyhow::Result<Self> {
+    pub async fn force_load(church_client: &crate::church::ChurchClient) -> anyhow::Result<Self> {
         let config_path =
             PathBuf::from_str(&church_client.env.working_path)?.join("holly_config.json");
         if !std::fs::exists(&config_path)? {
@@ -45,27 +49,19 @@ impl Config {
 
     pub async fn update(
         &mut self,
-        church_client: &mut crate::church::ChurchClient,
+        church_client: &crate::church::ChurchClient,
     ) -> anyhow::Result<()> {
         println!("Getting the newest data about zone chats...");
         let person_list = church_client.get_cached_people_list().await?;
-        let mut zones = Vec::new();
-        let mut zone_ids = HashSet::new();
-        for p in person_list {
-            if let (Some(zone_id), Some(zone_name)) = (p.zone_id, p.zone_name) {
-                if zone_ids.insert(zone_id) {
-                    zones.push((zone_id, zone_name));
-                }
-            }
-        }
+        let mission = Mission::from_people(&person_list);
 
-        for (zone_id, zone_name) in zones {
+        for zone in mission.zones.values() {
             let blank = "".to_string();
-            let past_id = self.zone_chats.get(&zone_id).unwrap_or(&blank);
+            let past_id = self.zone_chats.get(&zone.id).unwrap_or(&blank);
             let messenger_id: String = Input::with_theme(&ColorfulTheme::default())
                 .with_prompt(format!(
                     "Enter the Messenger zone chat ID for {}. Leave blank to skip.",
-                    zone_name
+                    zone.name
                 ))
                 .allow_empty(true)
                 .default(past_id.to_string())
@@ -73,9 +69,35 @@ impl Config {
                 .unwrap();
 
             if messenger_id.is_empty() {
-                self.zone_chats.remove(&zone_id);
+                self.zone_chats.remove(&zone.id);
             } else {
-                self.zone_chats.insert(zone_id, messenger_id);
+                self.zone_chats.insert(zone.id, messenger_id);
+            }
+        }
+
+        let has_district_chats = self.unit_chats.keys().any(|u| u.level() == Level::District);
+        if Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Send districts their own lists too?")
+            .default(has_district_chats)
+            .interact()
+            .unwrap()
+        {
+            for unit in mission.units(Level::District) {
+                let past_id = self.unit_chats.get(&unit).cloned().unwrap_or_default();
+                let messenger_id: String = Input::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!(
+                        "Enter the Messenger district chat ID for {}. Leave blank to skip.",
+                        mission.name_of(&unit)
+                    ))
+                    .allow_empty(true)
+                    .default(past_id)
+                    .interact_text()
+                    .unwrap();
+                if messenger_id.is_empty() {
+                    self.unit_chats.remove(&unit);
+                } else {
+                    self.unit_chats.insert(unit, messenger_id);
+                }
             }
         }
 
@@ -95,6 +117,22 @@ impl Config {
             self.unassigned_chat = Some(messenger_id)
         }
 
+        let policies = crate::policy::Policies::load(&church_client.env)?;
+        let mut names = vec![format!("Same as POLICY ({})", church_client.env.policy)];
+        names.extend(policies.0.keys().cloned());
+        let current = self
+            .policy
+            .as_ref()
+            .and_then(|p| names.iter().position(|n| n == p))
+            .unwrap_or(0);
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Which policy decides who is uncontacted in Holly's messages?")
+            .default(current)
+            .items(&names)
+            .interact()
+            .unwrap();
+        self.policy = (selection > 0).then(|| names[selection].clone());
+
         let last_transfer_start = chrono::DateTime::from_timestamp(self.last_transfer_start, 0)
             .unwrap_or_default()
             .date_naive();
@@ -146,8 +184,10 @@ impl Default for Config {
             last_transfer_start: chrono::Utc::now().timestamp(),
             zone_chats: Default::default(),
             unassigned_chat: None,
+            unit_chats: Default::default(),
             holly_socket: "127.0.0.1:8011".to_string(),
             name: "Holly".to_string(),
+            policy: None,
             blacklist: None,
         }
     }
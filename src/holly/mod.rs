@@ -5,14 +5,19 @@ use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     sync::mpsc::UnboundedSender,
-    time::sleep_until,
+    time::{sleep_until, Duration},
 };
 
-use crate::church::ChurchClient;
+use crate::{church::ChurchClient, error::ChurchError, org::Level};
 
 pub mod config;
 mod send_time;
 
+/// How long to wait before trying to build the list again after it failed
+const RETRY_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How long to wait when rate limited and the server didn't say how long
+const RATE_LIMIT_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Message {
     sender: String,
@@ -26,7 +31,7 @@ impl Message {
     }
 }
 
-pub async fn main(church_client: &mut ChurchClient) -> anyhow::Result<()> {
+pub async fn main(church_client: &ChurchClient) -> anyhow::Result<()> {
     info!("Connecting to Holly...");
     let holly_config = church_client
         .holly_config
@@ -36,6 +41,8 @@ pub async fn main(church_client: &mut ChurchClient) -> anyhow::Result<()> {
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
     tokio::task::spawn_blocking(move || user_input_loop(tx));
 
+    // Whether it's been time to send the list, but it hasn't gone out yet
+    let mut pending = false;
     loop {
         let mut stream = tokio::net::TcpStream::connect(&holly_config.holly_socket).await?;
         let mut next_time_check = tokio::time::Instant::now() + tokio::time::Duration::from_secs(1);
@@ -74,44 +81,32 @@ pub async fn main(church_client: &mut ChurchClient) -> anyhow::Result<()> {
                     next_time_check = tokio::time::Instant::now() + tokio::time::Duration::from_secs(20);
                     let mut st = send_time::SendTime::load(&church_client.env).await?;
                     if st.is_go_time().await? {
+                        pending = true;
+                    }
+                    if pending {
                         info!("Sending Holly's list!");
-                        let report = if let Some(report) = crate::report::Report::read_report(&church_client.env)? {
-                            report
-                        } else {
-                            crate::generate_report(church_client).await?
-                        };
-
-                        let contacts = crate::get_average(church_client).await?;
-                        let mut contacts = contacts.into_iter().collect::<Vec<(String, usize)>>();
-                        contacts.sort_unstable_by(|a, b| a.1.cmp(&b.1));
-
-                        let mut avg_report = "".to_string();
-                        for (k, v) in contacts {
-                            if let Some(bl) = &holly_config.blacklist {
-                                if bl.contains(&k) {
-                                    continue;
-                                }
-                            }
-                            let hours = v / 60;
-                            let minutes = v % 60;
-                            avg_report = format!("{avg_report}\n{k}: {hours}h {minutes}m");
+                        church_client.take_retry_stats();
+                        let res = build_messages(church_client, &holly_config).await;
+                        if let Err(e) = church_client.save_changed_cookies().await {
+                            error!("Unable to save cookies: {e}");
                         }
-                        for (zone_id, chat_id) in &holly_config.zone_chats {
-                            let msg = if let Some(p) = report.get_pretty_zone(zone_id) {
-                                format!("Good morning Zone!! The Lord has big plans for today - let's get started!\n\nAverage contact time over the past 24 hours:\n{avg_report}\n\nThese friends have not been successfully contacted yet. Please continue to be creative and persistent in your contacting!\n\n{p}")
-                            } else {
-                                info!("No uncontacted referrals in {zone_id}");
-                                format!("Good morning Zone!! The Lord has big plans for today - let's get started!\n\nAverage contact time over the past 24 hours:\n{avg_report}\n\nNo uncontacted referrals! GREAT work!")
-                            };
-                            info!("Sending {msg} to {chat_id}");
-                            stream.write_all(&Message { content: msg, chat_id: chat_id.to_string(), ..Default::default() }.to_bytes()).await?;
+                        let retries = church_client.take_retry_stats();
+                        if !retries.is_empty() {
+                            info!("{retries}");
                         }
-                        if let Some(chat_id) = &holly_config.unassigned_chat {
-                            let msg = report.unassigned.join("\n");
-                            info!("Sending {msg} to {chat_id}");
-                            stream.write_all(&Message { content: msg, chat_id: chat_id.to_string(), ..Default::default() }.to_bytes()).await?;
+                        match res {
+                            Ok(messages) => {
+                                pending = false;
+                                for msg in messages {
+                                    info!("Sending {} to {}", msg.content, msg.chat_id);
+                                    stream.write_all(&msg.to_bytes()).await?;
+                                }
+                            }
+                            Err(e) => {
+                                let delay = retry_delay(e)?;
+                                next_time_check = tokio::time::Instant::now() + delay;
+                            }
                         }
-
                     }
                 }
                 _ = rx.recv() => {
@@ -126,9 +121,219 @@ pub async fn main(church_client: &mut ChurchClient) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// How long to wait before trying to build the list again.
+/// Bad credentials won't fix themselves and retrying could lock the account, so those are returned.
+fn retry_delay(e: anyhow::Error) -> anyhow::Result<Duration> {
+    let delay = match e.downcast_ref::<ChurchError>().map(|c| c.root()) {
+        Some(ChurchError::Auth(_)) => return Err(e),
+        Some(c @ ChurchError::RateLimited { .. }) => c.retry_after().unwrap_or(RATE_LIMIT_BACKOFF),
+        _ => RETRY_BACKOFF,
+    };
+    error!(
+        "Unable to build Holly's list, trying again in {}m: {e:?}",
+        delay.as_secs() / 60
+    );
+    Ok(delay)
+}
+
+/// Builds the morning messages for each zone chat and the referral secretary
+async fn build_messages(
+    church_client: &ChurchClient,
+    holly_config: &config::Config,
+) -> anyhow::Result<Vec<Message>> {
+    let policy = crate::policy::Policy::load(
+        &church_client.env,
+        holly_config
+            .policy
+            .as_deref()
+            .unwrap_or(&church_client.env.policy),
+    )?;
+    let report = if let Some(report) =
+        crate::report::Report::read_report(&church_client.env, &policy.name)?
+    {
+        report
+    } else {
+        crate::generate_report(church_client, &policy).await?
+    };
+
+    // The blacklist is zones, whatever the averages are grouped by
+    let skip_zones = holly_config.blacklist.clone().unwrap_or_default();
+    let contacts = crate::get_average(church_client, &policy, &skip_zones).await?;
+
+    let window = policy.average_window_hours;
+    let mut avg_report = "".to_string();
+    for (k, v) in contacts {
+        let hours = v / 60;
+        let minutes = v % 60;
+        avg_report = format!("{avg_report}\n{k}: {hours}h {minutes}m");
+    }
+
+    let mut res = Vec::new();
+    // Zone chats go out in the order the report lists zones, then any without referrals
+    let zone_order = report
+        .zones(&crate::org::OrgUnit::Mission)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect::<Vec<usize>>();
+    let mut zone_chats = holly_config.zone_chats.iter().collect::<Vec<_>>();
+    zone_chats.sort_by_key(|(id, _)| {
+        (
+            zone_order
+                .iter()
+                .position(|z| z == *id)
+                .unwrap_or(usize::MAX),
+            **id,
+        )
+    });
+    for (zone_id, chat_id) in zone_chats {
+        let msg = if let Some(p) = report.get_pretty_zone(zone_id) {
+            format!("Good morning Zone!! The Lord has big plans for today - let's get started!\n\nAverage contact time over the past {window} hours:\n{avg_report}\n\nThese friends have not been successfully contacted yet. Please continue to be creative and persistent in your contacting!\n\n{p}")
+        } else {
+            info!("No uncontacted referrals in {zone_id}");
+            format!("Good morning Zone!! The Lord has big plans for today - let's get started!\n\nAverage contact time over the past {window} hours:\n{avg_report}\n\nNo uncontacted referrals! GREAT work!")
+        };
+        res.push(Message {
+            content: msg,
+            chat_id: chat_id.to_string(),
+            ..Default::default()
+        });
+    }
+    let mut units = holly_config.unit_chats.iter().collect::<Vec<_>>();
+    units.sort();
+    for (unit, chat_id) in units {
+        let greeting = match unit.level() {
+            Level::Mission => "Good morning Mission!!",
+            Level::Zone => "Good morning Zone!!",
+            Level::District => "Good morning District!!",
+            Level::Area => "Good morning!!",
+        };
+        let msg = if let Some(p) = report.get_pretty_unit(unit) {
+            format!("{greeting} The Lord has big plans for today - let's get started!\n\nThese friends have not been successfully contacted yet. Please continue to be creative and persistent in your contacting!\n\n{p}")
+        } else {
+            info!("No uncontacted referrals in {unit}");
+            format!("{greeting} The Lord has big plans for today - let's get started!\n\nNo uncontacted referrals! GREAT work!")
+        };
+        res.push(Message {
+            content: msg,
+            chat_id: chat_id.to_string(),
+            ..Default::default()
+        });
+    }
+    if let Some(chat_id) = &holly_config.unassigned_chat {
+        let mut content = report
+            .unassigned()
+            .map(|e| e.line())
+            .collect::<Vec<String>>()
+            .join("\n");
+        if !report.duplicates.is_empty() {
+            content = format!(
+                "{content}\n\nThese referrals look like the same friend, please check them in referral manager:\n{}",
+                report.duplicates.join("\n")
+            );
+        }
+        res.push(Message {
+            content,
+            chat_id: chat_id.to_string(),
+            ..Default::default()
+        });
+    }
+    Ok(res)
+}
+
 fn user_input_loop(sender: UnboundedSender<()>) {
     println!("Press 'q' and then enter to disconnect from Holly gracefully.");
     let mut buf = String::new();
     let _ = std::io::stdin().read_line(&mut buf).is_ok();
     let _ = sender.send(()).is_ok();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{self, MockServer};
+    use crate::org::OrgUnit;
+
+    #[tokio::test]
+    async fn morning_messages() {
+        let server = MockServer::with_fixtures().await;
+        let client = crate::church::ChurchClient::new(server.env())
+            .await
+            .unwrap();
+        let config = super::config::Config {
+            zone_chats: [
+                (mock::NORTH.0, "north-chat".to_string()),
+                (99, "empty-chat".to_string()),
+            ]
+            .into(),
+            unassigned_chat: Some("secretary-chat".to_string()),
+            unit_chats: [
+                (
+                    OrgUnit::District(mock::SOUTH.0 * 10),
+                    "south-district".to_string(),
+                ),
+                (
+                    OrgUnit::Area(mock::NORTH.0, "Alpha".to_string()),
+                    "alpha-chat".to_string(),
+                ),
+            ]
+            .into(),
+            blacklist: Some(vec!["South".to_string()]),
+            ..Default::default()
+        };
+
+        let messages = super::build_messages(&client, &config).await.unwrap();
+        assert_eq!(messages.len(), 5);
+        let get = |chat: &str| {
+            messages
+                .iter()
+                .find(|m| m.chat_id == chat)
+                .unwrap()
+                .content
+                .clone()
+        };
+        let north = get("north-chat");
+        assert!(north.contains("North: ") && !north.contains("South: "));
+        assert!(north.contains("over the past 24 hours"));
+        assert!(north.contains("Ana Lopez") && north.contains("Elder Smith & Elder Jones"));
+        assert!(get("empty-chat").contains("No uncontacted referrals!"));
+        let district = get("south-district");
+        assert!(district.contains("South District") && district.contains("Dee"));
+        assert!(!district.contains("Ana"));
+        let alpha = get("alpha-chat");
+        assert!(alpha.contains("Ana") && !alpha.contains("Dee"));
+        assert_eq!(get("secretary-chat"), "Fay");
+
+        // Blacklisted zones stay out when averages are grouped by district
+        let mut env = server.env();
+        env.average_by = crate::org::Level::District;
+        let client = crate::church::ChurchClient::new(env).await.unwrap();
+        let messages = super::build_messages(&client, &config).await.unwrap();
+        let north = &messages
+            .iter()
+            .find(|m| m.chat_id == "north-chat")
+            .unwrap()
+            .content;
+        assert!(north.contains("North District: ") && !north.contains("South District: "));
+    }
+
+    #[test]
+    fn retry_delays() {
+        use crate::error::ChurchError;
+        let auth = anyhow::Error::from(ChurchError::Auth("Password is incorrect".to_string()));
+        assert!(super::retry_delay(auth).is_err());
+
+        let limited = ChurchError::RetriesExhausted {
+            attempts: 3,
+            last: Box::new(ChurchError::RateLimited {
+                url: "http://localhost".to_string(),
+                retry_after: Some(super::Duration::from_secs(90)),
+            }),
+        };
+        assert_eq!(
+            super::retry_delay(limited.into()).unwrap(),
+            super::Duration::from_secs(90)
+        );
+
+        let other = anyhow::anyhow!("Holly went away");
+        assert_eq!(super::retry_delay(other).unwrap(), super::RETRY_BACKOFF);
+    }
+}
@@ -0,0 +1,195 @@
+// Jackson Coxson
+// Models for the Okta IDX login flow on id.churchofjesuschrist.org
+
+use serde::Deserialize;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::error::ChurchError;
+
+/// A response from any of the /idp/idx endpoints.
+/// Okta says what it wants next through the remediations, so the flow isn't fixed.
+#[derive(Debug, Deserialize)]
+pub struct IdxResponse {
+    #[serde(rename = "stateHandle")]
+    pub state_handle: Option<String>,
+
+    pub remediation: Option<Collection<Remediation>>,
+
+    #[serde(rename = "currentAuthenticator")]
+    pub current_authenticator: Option<Single<Authenticator>>,
+
+    pub authenticators: Option<Collection<Authenticator>>,
+
+    pub success: Option<Success>,
+
+    pub messages: Option<Collection<Message>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Collection<T> {
+    pub value: Vec<T>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Single<T> {
+    pub value: T,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Remediation {
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Authenticator {
+    pub id: Option<String>,
+
+    #[serde(rename = "type")]
+    pub kind: AuthenticatorKind,
+
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+
+    #[serde(default)]
+    pub methods: Vec<Method>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthenticatorKind {
+    Password,
+    /// Google Authenticator, Okta Verify and other TOTP apps
+    App,
+    /// SMS or voice codes
+    Phone,
+    Email,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Method {
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Message {
+    pub message: String,
+    pub class: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Success {
+    pub href: String,
+}
+
+/// What the login flow has to do next
+#[derive(Debug)]
+pub enum Step {
+    /// Logged in, follow this link to get the session cookies
+    Success(String),
+    /// Answer the password challenge
+    Password,
+    /// Answer a one time code challenge from this authenticator
+    Code(Authenticator),
+    /// Pick one of these authenticators to be challenged with
+    Select(Vec<Authenticator>),
+}
+
+impl IdxResponse {
+    /// The error messages Okta sent back, like a wrong password or code
+    pub fn errors(&self) -> Option<String> {
+        let messages = self
+            .messages
+            .as_ref()?
+            .value
+            .iter()
+            .filter(|m| m.class.as_deref().unwrap_or("ERROR") == "ERROR")
+            .map(|m| m.message.as_str())
+            .collect::<Vec<&str>>();
+        if messages.is_empty() {
+            None
+        } else {
+            Some(messages.join(", "))
+        }
+    }
+
+    pub fn next_step(self) -> Result<Step, ChurchError> {
+        if let Some(success) = self.success {
+            return Ok(Step::Success(success.href));
+        }
+        let names = self
+            .remediation
+            .map(|r| r.value.into_iter().map(|r| r.name).collect::<Vec<String>>())
+            .unwrap_or_default();
+
+        if names.iter().any(|n| n == "challenge-authenticator") {
+            let authenticator = self
+                .current_authenticator
+                .ok_or_else(|| {
+                    ChurchError::Parse("a challenge without a current authenticator".to_string())
+                })?
+                .value;
+            return Ok(match authenticator.kind {
+                AuthenticatorKind::Password => Step::Password,
+                _ => Step::Code(authenticator),
+            });
+        }
+        if names
+            .iter()
+            .any(|n| n == "select-authenticator-authenticate")
+        {
+            let authenticators = self
+                .authenticators
+                .map(|a| a.value)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|a| a.id.is_some())
+                .collect::<Vec<Authenticator>>();
+            if authenticators.is_empty() {
+                return Err(ChurchError::Parse(
+                    "an authenticator selection without authenticators".to_string(),
+                ));
+            }
+            return Ok(Step::Select(authenticators));
+        }
+        Err(ChurchError::Auth(format!(
+            "Unsupported login step, the server asked for: {}",
+            names.join(", ")
+        )))
+    }
+}
+
+impl Authenticator {
+    pub fn name(&self) -> String {
+        self.display_name
+            .clone()
+            .unwrap_or_else(|| format!("{:?}", self.kind))
+    }
+
+    /// The method to ask for when selecting this authenticator.
+    /// Okta Verify can push or give a code, and we can only handle codes.
+    pub fn method_type(&self) -> Option<&str> {
+        if self.methods.iter().any(|m| m.kind == "totp") {
+            return Some("totp");
+        }
+        self.methods.first().map(|m| m.kind.as_str())
+    }
+}
+
+/// Builds the TOTP generator for a base32 secret, the same one the authenticator app has.
+/// Spaces and lowercase are fine since that's how most sites display the secret.
+pub fn totp(secret: &str) -> Result<TOTP, ChurchError> {
+    let secret = secret.replace(' ', "").trim_end_matches('=').to_uppercase();
+    let secret = Secret::Encoded(secret)
+        .to_bytes()
+        .map_err(|e| ChurchError::Auth(format!("The TOTP secret isn't valid base32: {e}")))?;
+    Ok(TOTP::new_unchecked(Algorithm::SHA1, 6, 1, 30, secret))
+}
+
+pub fn totp_code(secret: &str) -> Result<String, ChurchError> {
+    totp(secret)?
+        .generate_current()
+        .map_err(|_| std::io::Error::other("Your clock is wrong").into())
+}
@@ -5,21 +5,44 @@ use std::collections::HashMap;
 use chrono::{Duration, Utc};
 use church::ChurchClient;
 use dialoguer::{theme::ColorfulTheme, Select};
+use error::ChurchError;
+use futures::{stream, StreamExt};
 use indicatif::ProgressBar;
-use log::info;
+use log::{info, warn};
 
 mod bearer;
+mod cassette;
 mod church;
+mod duplicates;
 mod env;
+mod error;
+mod filter;
+mod history;
 mod holly;
+mod idx;
+#[cfg(test)]
+mod mock;
+mod org;
 mod persons;
+mod policy;
+mod render;
 mod report;
+mod retry;
+mod snapshots;
 
-const CLI_OPTIONS: [&str; 6] = ["report", "generate", "average", "holly", "settings", "exit"];
-const CLI_DESCRIPTONS: [&str; 6] = [
+const CLI_OPTIONS: [&str; 11] = [
+    "report", "generate", "history", "compare", "average", "changes", "diagnose", "policies",
+    "holly", "settings", "exit",
+];
+const CLI_DESCRIPTONS: [&str; 11] = [
     "Reads today's report of uncontacted referrals or fetches a new one",
     "Generates a new list of uncontacted referrals, regardless of the cache.",
+    "Lists the reports saved on earlier days, or shows the one for a date",
+    "Compares two days' reports, the latest and the one before unless dates are given",
     "Gets the average contact time in minutes between zones",
+    "Shows who changed status, area or zone since yesterday or an earlier fetch",
+    "Shows which records from referral manager couldn't be read and why",
+    "Lists the policies for who counts as uncontacted and picks one to use",
     "Connects to Holly and responds to messages",
     "Change the settings for Holly",
     "Exits the program",
@@ -28,14 +51,44 @@ const CLI_DESCRIPTONS: [&str; 6] = [
 #[tokio::main]
 async fn main() {
     println!("Starting referral list program... Checking environment...");
-    let env = env::check_vars();
+    let env = match env::check_vars() {
+        Ok(env) => env,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
     env_logger::init();
     let mut church_client = church::ChurchClient::new(env).await.unwrap();
 
-    let mut args = std::env::args();
-    if args.len() > 1 {
-        if let Err(e) = parse_argument(&args.nth(1).unwrap(), &mut church_client).await {
-            println!("Ran into an error while processing: {e:?}");
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    if let Some(command) = args.first() {
+        // Anything before the first option, like dates, goes to the command
+        let params = args[1..]
+            .iter()
+            .take_while(|a| !a.starts_with('-'))
+            .cloned()
+            .collect::<Vec<String>>();
+        let mut options = args[1 + params.len()..].to_vec();
+        let res = match policy::Policy::take_arg(&mut options) {
+            Ok(name) => {
+                if let Some(name) = name {
+                    church_client.env.policy = name;
+                }
+                match render::Output::from_args(&options) {
+                    Ok(output) => {
+                        let res =
+                            parse_argument(command, &params, &output, &mut church_client).await;
+                        save_cookies(&church_client).await;
+                        res
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            print_error(&e);
         }
         return;
     }
@@ -54,38 +107,190 @@ async fn main() {
             .interact()
             .unwrap();
 
-        match parse_argument(CLI_OPTIONS[selection], &mut church_client).await {
+        let output = render::Output::default();
+        let res = parse_argument(CLI_OPTIONS[selection], &[], &output, &mut church_client).await;
+        save_cookies(&church_client).await;
+        match res {
             Ok(true) => continue,
             Ok(false) => return,
             Err(e) => {
-                println!("Ran into an error while processing: {e:?}");
+                print_error(&e);
+            }
+        }
+    }
+}
+
+/// Keeps any cookies the command's requests refreshed for next time
+async fn save_cookies(church_client: &ChurchClient) {
+    if let Err(e) = church_client.save_changed_cookies().await {
+        warn!("Unable to save cookies: {e}");
+    }
+}
+
+/// Whether someone is there to answer a prompt, instead of a script or a scheduled run
+fn interactive() -> bool {
+    std::io::IsTerminal::is_terminal(&std::io::stdin())
+}
+
+fn print_error(e: &anyhow::Error) {
+    println!("Ran into an error while processing: {e:?}");
+    if let Some(e) = e.downcast_ref::<ChurchError>() {
+        match e.root() {
+            ChurchError::Auth(_) => println!(
+                "Check CHURCH_USERNAME, CHURCH_PASSWORD and CHURCH_TOTP_SECRET in your .env file"
+            ),
+            ChurchError::RateLimited { .. } => {
+                println!("The church servers are asking us to slow down, try again later")
             }
+            ChurchError::Network(_) => println!("Check your internet connection"),
+            _ => (),
         }
     }
 }
 
-async fn parse_argument(arg: &str, church_client: &mut ChurchClient) -> anyhow::Result<bool> {
+async fn parse_argument(
+    arg: &str,
+    params: &[String],
+    output: &render::Output,
+    church_client: &mut ChurchClient,
+) -> anyhow::Result<bool> {
     match arg {
         "report" => {
-            if let Some(report) = report::Report::read_report(&church_client.env)? {
-                println!("{}", report.pretty_print());
-            } else {
-                let report = generate_report(church_client).await?;
-                println!("{}", report.pretty_print());
-            }
+            let report =
+                match report::Report::read_report(&church_client.env, &church_client.env.policy)? {
+                    Some(report) => report,
+                    None => {
+                        let policy =
+                            policy::Policy::load(&church_client.env, &church_client.env.policy)?;
+                        generate_report(church_client, &policy).await?
+                    }
+                };
+            output.write(&report)?;
             Ok(true)
         }
         "generate" => {
-            generate_report(church_client).await?;
+            let policy = policy::Policy::load(&church_client.env, &church_client.env.policy)?;
+            let report = generate_report(church_client, &policy).await?;
+            // Only written out when asked for, generating is usually just to refresh the cache
+            if output.path.is_some() {
+                output.write(&report)?;
+            }
+            Ok(true)
+        }
+        "history" => {
+            let days = history::days(&church_client.env)?;
+            let day = match params.first() {
+                Some(day) => Some(history::parse_day(day)?),
+                None if days.is_empty() => {
+                    println!("No reports have been saved yet");
+                    None
+                }
+                None if interactive() => {
+                    let options = days.iter().rev().map(|d| d.to_string()).collect::<Vec<_>>();
+                    let selection = Select::with_theme(&ColorfulTheme::default())
+                        .with_prompt("Show the report from which day?")
+                        .default(0)
+                        .items(&options)
+                        .interact()?;
+                    Some(days[days.len() - 1 - selection])
+                }
+                None => {
+                    for day in &days {
+                        println!("{day}");
+                    }
+                    None
+                }
+            };
+            if let Some(day) = day {
+                match report::Report::read_report_on(
+                    &church_client.env,
+                    day,
+                    &church_client.env.policy,
+                )? {
+                    Some(report) => output.write(&report)?,
+                    None => println!("There's no report saved for {day}"),
+                }
+            }
+            Ok(true)
+        }
+        "compare" => {
+            let days = history::days(&church_client.env)?;
+            let (from, to) = match params {
+                [from, to, ..] => (history::parse_day(from)?, history::parse_day(to)?),
+                // Against the report saved before the given day
+                [to] => {
+                    let to = history::parse_day(to)?;
+                    match days.iter().rev().find(|d| **d < to) {
+                        Some(from) => (*from, to),
+                        None => return Err(anyhow::anyhow!("No report was saved before {to}")),
+                    }
+                }
+                [] if days.len() < 2 => {
+                    println!("Comparing needs reports from at least two days");
+                    return Ok(true);
+                }
+                [] => (days[days.len() - 2], days[days.len() - 1]),
+            };
+            println!(
+                "{}",
+                history::ReportDiff::load(&church_client.env, from, to)?
+            );
             Ok(true)
         }
         "average" => {
-            let contacts = get_average(church_client).await?;
+            let policy = policy::Policy::load(&church_client.env, &church_client.env.policy)?;
+            let contacts = get_average(church_client, &policy, &[]).await?;
             for (k, v) in contacts {
                 println!("{k}: {v}");
             }
             Ok(true)
         }
+        "changes" => {
+            match people_changes(church_client, interactive()).await? {
+                Some((since, diff)) => println!("Changes since {since}\n{diff}"),
+                None => println!("No earlier people lists to compare against yet"),
+            }
+            Ok(true)
+        }
+        "diagnose" => {
+            church_client.get_cached_people_list().await?;
+            println!("People list\n{}", church_client.people_diagnostics());
+            println!(
+                "\nCached timelines\n{}",
+                church_client.cached_timeline_diagnostics()?
+            );
+            Ok(true)
+        }
+        "policies" => {
+            let policies = policy::Policies::load(&church_client.env)?;
+            for (name, policy) in &policies.0 {
+                let chosen = if *name == church_client.env.policy {
+                    " (in use)"
+                } else {
+                    ""
+                };
+                println!("{name}{chosen}: {policy}");
+            }
+            println!(
+                "Edit {} in the working path to add or change policies",
+                policy::POLICIES_FILE
+            );
+            if interactive() {
+                let names = policies.0.keys().cloned().collect::<Vec<String>>();
+                let selection = Select::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Use which policy for the rest of this session?")
+                    .default(
+                        names
+                            .iter()
+                            .position(|n| *n == church_client.env.policy)
+                            .unwrap_or(0),
+                    )
+                    .items(&names)
+                    .interact()?;
+                church_client.env.policy = names[selection].clone();
+            }
+            Ok(true)
+        }
         "holly" => {
             holly::main(church_client).await?;
             Ok(false)
@@ -107,6 +312,9 @@ async fn parse_argument(arg: &str, church_client: &mut ChurchClient) -> anyhow::
             for i in 0..CLI_OPTIONS.len() {
                 println!("  {} - {}", CLI_OPTIONS[i], CLI_DESCRIPTONS[i]);
             }
+            println!("Reports can be written with --format text|markdown|html|csv|json and --output FILE");
+            println!("  history [YYYY-MM-DD] and compare [FROM] [TO] take dates after the command");
+            println!("  --policy NAME picks the policy for who is uncontacted, see policies");
             Ok(false)
         }
         _ => Err(anyhow::anyhow!(
@@ -115,84 +323,534 @@ async fn parse_argument(arg: &str, church_client: &mut ChurchClient) -> anyhow::
     }
 }
 
-pub async fn generate_report(church_client: &mut ChurchClient) -> anyhow::Result<report::Report> {
+/// Compares the current people list with an earlier snapshot. Without `choose`, or if
+/// it's picked, that's the last one from at least a day ago, or the oldest there is.
+pub async fn people_changes(
+    church_client: &ChurchClient,
+    choose: bool,
+) -> anyhow::Result<Option<(snapshots::Snapshot, snapshots::PeopleDiff)>> {
+    let current = church_client.get_cached_people_list().await?;
+    let mut earlier = snapshots::Snapshot::list(&church_client.env)?;
+    // The newest one is the list we just got
+    earlier.pop();
+    if earlier.is_empty() {
+        return Ok(None);
+    }
+
+    let yesterday = (Utc::now() - Duration::days(1)).timestamp() as u64;
+    let mut since = snapshots::Snapshot::latest_before(&earlier, yesterday)
+        .unwrap_or(&earlier[0])
+        .clone();
+    if choose {
+        let mut options = vec![format!("Since yesterday ({since})")];
+        options.extend(earlier.iter().rev().map(|s| s.to_string()));
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("Compare with which people list?")
+            .default(0)
+            .items(&options)
+            .interact()?;
+        if selection > 0 {
+            since = earlier[earlier.len() - selection].clone();
+        }
+    }
+
+    let (old, _) = since.load()?.into_parts();
+    let diff = snapshots::PeopleDiff::between(&old, &current);
+    Ok(Some((since, diff)))
+}
+
+pub async fn generate_report(
+    church_client: &ChurchClient,
+    policy: &policy::Policy,
+) -> anyhow::Result<report::Report> {
+    // Only count this run's retries and timelines
+    church_client.take_retry_stats();
+    church_client.take_timeline_diagnostics();
     let persons_list = church_client.get_cached_people_list().await?;
+    let diagnostics = church_client.people_diagnostics();
+    if diagnostics.is_concerning() {
+        println!(
+            "Warning: {} of {} people from referral manager couldn't be read and are missing from this report. Run `diagnose` to see why.",
+            diagnostics.failed, diagnostics.total
+        );
+    }
+    // Counted before the policy picks anyone, so nobody drops off without a warning
+    let unknown = persons_list
+        .iter()
+        .filter(|p| p.has_unknown_status())
+        .count();
+    if unknown > 0 {
+        println!("{unknown} referrals have a status this version doesn't know about and are treated as not members yet, check for an update");
+    }
     let now = Utc::now().naive_utc();
-    let persons_list: Vec<persons::Person> = persons_list
+    let rule = policy.report_filter();
+    let filter = &church_client.env.report_filter;
+    let uncontacted = |x: &persons::Person| rule.matches(x, now) && filter.matches(x, now);
+    // Copies that were contacted count too, so look over everyone
+    let duplicates = duplicates::find_duplicates(&persons_list)
         .into_iter()
-        .filter(|x| {
-            (x.referral_status != persons::ReferralStatus::Successful
-                && x.person_status < persons::PersonStatus::NewMember
-                && now.signed_duration_since(x.assigned_date) > Duration::hours(48))
-            || x.referral_status == persons::ReferralStatus::NotAttempted
-        })
+        .filter(|g| g.people.iter().any(uncontacted))
+        .map(|g| g.report_line())
+        .collect::<Vec<String>>();
+    if !duplicates.is_empty() {
+        println!(
+            "{} groups of uncontacted referrals look like the same friend",
+            duplicates.len()
+        );
+    }
+    let mut persons_list: Vec<persons::Person> = persons_list
+        .into_iter()
+        .filter(|x| uncontacted(x))
         .collect();
+    // Only collapsed among the uncontacted, so a reached copy can't hide a new one
+    if church_client.env.collapse_duplicates {
+        persons_list = duplicates::collapse(persons_list).0;
+    }
     info!("{} uncontacted referrals", persons_list.len());
 
     let mut report = report::Report::new();
+    report.duplicates = duplicates;
+    report.policy = policy.name.clone();
     let bar = ProgressBar::new(persons_list.len() as u64);
-    for person in persons_list {
+    let mut failed = 0;
+    let mut contacts = stream::iter(persons_list)
+        .map(|person| async {
+            let last_contact = church_client.get_person_last_contact(&person).await;
+            (person, last_contact)
+        })
+        .buffer_unordered(church_client.env.concurrency);
+    while let Some((person, last_contact)) = contacts.next().await {
         bar.inc(1);
-        if match church_client.get_person_last_contact(&person).await? {
-            Some(t) => now.signed_duration_since(t) > Duration::hours(48),
-            None => true,
-        } {
-            report.add_person(person);
+        match last_contact {
+            Ok(Some(t)) if policy.recently_contacted(t, now) => {}
+            Ok(last_contact) => report.add_person(person, last_contact),
+            Err(e) => timeline_failed(&person, e, &mut failed)?,
         }
     }
+    bar.finish();
+    if failed > 0 {
+        println!(
+            "Couldn't get the timeline for {failed} people, they are missing from this report"
+        );
+    }
+    let diagnostics = church_client.take_timeline_diagnostics();
+    if diagnostics.failed > 0 {
+        println!(
+            "Warning: {} timeline events couldn't be read, some last contacts may be wrong. Run `diagnose` to see why.",
+            diagnostics.failed
+        );
+    }
+    let retries = church_client.take_retry_stats();
+    if !retries.is_empty() {
+        println!("{retries}");
+    }
 
+    report.sort(&church_client.env.report_order);
     report.save_report(&church_client.env)?;
     Ok(report)
 }
 
+/// Counts someone whose timeline couldn't be fetched. Bad credentials end the whole run,
+/// since everyone else would fail to log in the same way.
+fn timeline_failed(
+    person: &persons::Person,
+    e: ChurchError,
+    failed: &mut usize,
+) -> anyhow::Result<()> {
+    if let ChurchError::Auth(_) = e {
+        return Err(e.into());
+    }
+    warn!("Unable to get the timeline for {}: {e}", person.guid);
+    *failed += 1;
+    Ok(())
+}
+
+/// Average contact times by group, leaving out anyone in `skip_zones`.
+/// Fastest first, then by name, so the order is the same every run.
 pub async fn get_average(
-    church_client: &mut ChurchClient,
-) -> anyhow::Result<HashMap<String, usize>> {
+    church_client: &ChurchClient,
+    policy: &policy::Policy,
+    skip_zones: &[String],
+) -> anyhow::Result<Vec<(String, usize)>> {
+    church_client.take_retry_stats();
     let mut contacts = church_client.env.load_contacts()?;
 
-    let persons_list = church_client.get_cached_people_list().await?.to_vec();
+    let persons_list = church_client.get_cached_people_list().await?;
     let now = Utc::now().naive_utc();
-    let persons_list: Vec<persons::Person> = persons_list
+    let rule = policy.average_filter();
+    let filter = &church_client.env.average_filter;
+    let mut persons_list: Vec<persons::Person> = persons_list
+        .into_iter()
+        .filter(|x| rule.matches(x, now) && filter.matches(x, now))
+        .collect();
+    if church_client.env.collapse_duplicates {
+        persons_list = duplicates::collapse(persons_list).0;
+    }
+    // Grouped by zone unless AVERAGE_BY says otherwise, skipping anyone not assigned that far
+    let level = church_client.env.average_by;
+    let persons_list: Vec<(String, persons::Person)> = persons_list
         .into_iter()
         .filter(|x| {
-            x.referral_status != persons::ReferralStatus::NotAttempted
-                && (x.person_status < persons::PersonStatus::NewMember)
-                && now.signed_duration_since(x.assigned_date) < Duration::hours(24)
+            !org::Level::Zone
+                .label(x)
+                .is_some_and(|z| skip_zones.contains(&z))
         })
+        .filter_map(|x| Some((level.label(&x)?, x)))
         .collect();
 
-    let mut zones = HashMap::new();
+    let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
     let bar = ProgressBar::new(persons_list.len() as u64);
-    for person in persons_list {
-        if let Some(zone_name) = &person.zone_name {
+    let mut to_fetch = Vec::new();
+    for (group, person) in persons_list {
+        if let Some(t) = contacts.get(&person.guid) {
             bar.inc(1);
-            let t = if let Some(t) = contacts.get(&person.guid) {
-                t.to_owned()
-            } else if let Some(t) = church_client.get_person_contact_time(&person).await? {
+            groups.entry(group).or_default().push(*t);
+        } else {
+            to_fetch.push((group, person));
+        }
+    }
+
+    let mut failed = 0;
+    let mut fetched = stream::iter(to_fetch)
+        .map(|(group, person)| async {
+            let contact_time = church_client.get_person_contact_time(&person).await;
+            (group, person, contact_time)
+        })
+        .buffer_unordered(church_client.env.concurrency);
+    while let Some((group, person, contact_time)) = fetched.next().await {
+        bar.inc(1);
+        match contact_time {
+            Ok(Some(t)) => {
+                groups.entry(group).or_default().push(t);
                 contacts.insert(person.guid, t);
-                t
-            } else {
-                continue;
-            };
-            let zone = match zones.get_mut(zone_name) {
-                Some(z) => z,
-                None => {
-                    zones.insert(zone_name.clone(), Vec::new());
-                    zones.get_mut(zone_name).unwrap()
-                }
-            };
-            zone.push(t);
+            }
+            Ok(None) => {}
+            Err(e) => timeline_failed(&person, e, &mut failed)?,
         }
     }
     bar.finish();
+    if failed > 0 {
+        println!(
+            "Couldn't get the timeline for {failed} people, they are missing from the average"
+        );
+    }
+    let retries = church_client.take_retry_stats();
+    if !retries.is_empty() {
+        println!("{retries}");
+    }
 
     church_client.env.save_contacts(&contacts)?;
 
-    let mut res = HashMap::new();
-    for (k, v) in zones {
-        let sum: usize = v.iter().sum();
-        let avg = sum / v.len();
-        res.insert(k, avg);
-    }
+    let mut res = groups
+        .into_iter()
+        .map(|(k, v)| {
+            let sum: usize = v.iter().sum();
+            (k, sum / v.len())
+        })
+        .collect::<Vec<(String, usize)>>();
+    res.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{self, MockServer};
+
+    #[tokio::test]
+    async fn report_lists_uncontacted() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+
+        let report = super::generate_report(&client, &Default::default())
+            .await
+            .unwrap();
+        let north = report.get_pretty_zone(&mock::NORTH.0).unwrap();
+        let south = report.get_pretty_zone(&mock::SOUTH.0).unwrap();
+        assert!(north.contains("Ana") && north.contains("Ben"));
+        assert!(north.contains("Ana Lopez (Female, Young adult, Spanish, via Facebook)"));
+        assert!(north.contains("- Ben Ng") && !north.contains("Ben Ng ("));
+        // Unknown statuses are still reported
+        assert!(north.contains("Ivy"));
+        assert!(!north.contains("Gus"));
+        assert!(south.contains("Dee"));
+        // Including ones that were tried and not reached
+        assert!(south.contains("- Jo"));
+        assert!(!south.contains("Cy") && !south.contains("Eve"));
+        let unassigned = report.unassigned().collect::<Vec<_>>();
+        assert_eq!(unassigned.len(), 1);
+        assert_eq!(unassigned[0].name, "Fay");
+        assert_eq!(unassigned[0].guid.as_deref(), Some("p-fay"));
+        assert_eq!(unassigned[0].hours_waiting, Some(50));
+        // Ana's timeline only has her referral, Dee was contacted days ago
+        let entry = |guid: &str| {
+            report
+                .entries
+                .iter()
+                .find(|e| e.guid.as_deref() == Some(guid))
+                .unwrap()
+        };
+        assert_eq!(entry("p-ana").last_contact, None);
+        assert!(entry("p-dee").last_contact.is_some());
+        assert_eq!(
+            entry("p-dee").person_status,
+            Some(crate::persons::PersonStatus::Yellow)
+        );
+
+        // The broken fixture is noticed, and remembered by the cached list
+        let diagnostics = client.people_diagnostics();
+        assert_eq!(diagnostics.failed, 1);
+        assert!(diagnostics.is_concerning());
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        client.get_cached_people_list().await.unwrap();
+        assert_eq!(client.people_diagnostics(), diagnostics);
+        assert_eq!(client.cached_timeline_diagnostics().unwrap().unknown, 1);
+
+        // The saved report is read back without touching the server
+        let saved = crate::report::Report::read_report(&client.env, crate::policy::DEFAULT_POLICY)
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.entries, report.entries);
+    }
+
+    #[tokio::test]
+    async fn policies_pick_who_is_reported() {
+        let server = MockServer::with_fixtures().await;
+        let mut env = server.env();
+        std::fs::write(
+            std::path::PathBuf::from(&env.working_path).join(crate::policy::POLICIES_FILE),
+            r#"{"strict": {"waiting_hours": 65, "recent_contact_hours": 96, "always_include_not_attempted": false}}"#,
+        )
+        .unwrap();
+        let client = super::ChurchClient::new(env.clone()).await.unwrap();
+        let names = |report: &crate::report::Report| {
+            let mut names = report
+                .entries
+                .iter()
+                .map(|e| e.name.clone())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        // Both policies run on the same day and keep their own reports
+        let default = super::generate_report(&client, &Default::default())
+            .await
+            .unwrap();
+        // Ben and Fay haven't waited long enough, Dee was contacted within four days,
+        // and Ivy's unknown status still counts as not a member yet
+        let strict = crate::policy::Policy::load(&client.env, "strict").unwrap();
+        let report = super::generate_report(&client, &strict).await.unwrap();
+        assert_eq!(names(&report), ["Ana Lopez", "Ivy"]);
+        assert!(crate::policy::Policy::load(&client.env, "default").is_err());
+
+        let read = |policy: &str| {
+            crate::report::Report::read_report(&client.env, policy)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(read("default").entries, default.entries);
+        assert_eq!(read("default").policy, "default");
+        assert_eq!(names(&read("strict")), ["Ana Lopez", "Ivy"]);
+        assert_eq!(read("strict").policy, "strict");
+        assert!(crate::report::Report::read_report(&client.env, "weekly")
+            .unwrap()
+            .is_none());
+        let mut strict_env = client.env.clone();
+        strict_env.policy = "strict".to_string();
+        assert_eq!(crate::history::days(&strict_env).unwrap().len(), 1);
+
+        // REPORT_FILTER narrows what the policy picks
+        env.report_filter = "zone=none".parse().unwrap();
+        let client = super::ChurchClient::new(env).await.unwrap();
+        let report = super::generate_report(&client, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(names(&report), ["Fay"]);
+    }
+
+    #[tokio::test]
+    async fn collapsing_keeps_new_referrals() {
+        let server = MockServer::start().await;
+        let phone = serde_json::json!({"phone": "555 010 0100"});
+        // Reached long ago, then referred again and not tried yet
+        server.add_person(mock::with(
+            mock::person("p-old", "Ana", 30, 1, Some(mock::NORTH), Some("Alpha"), 500),
+            phone.clone(),
+        ));
+        server.add_person(mock::with(
+            mock::person("p-new", "Ana", 10, 1, Some(mock::NORTH), Some("Alpha"), 72),
+            phone,
+        ));
+        let mut env = server.env();
+        env.collapse_duplicates = true;
+        let client = super::ChurchClient::new(env).await.unwrap();
+
+        let report = super::generate_report(&client, &Default::default())
+            .await
+            .unwrap();
+        let guids = report
+            .entries
+            .iter()
+            .map(|e| e.guid.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(guids, ["p-new"]);
+        assert_eq!(report.duplicates.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn changes_since_yesterday() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        assert!(super::people_changes(&client, false)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Two days ago Ana was in South, and Zed was still around
+        let now = chrono::Utc::now().timestamp();
+        let mut old = mock::fixture_people();
+        old[0]["zoneId"] = serde_json::json!(mock::SOUTH.0);
+        old[0]["zoneName"] = serde_json::json!(mock::SOUTH.1);
+        old[1]["personStatusId"] = serde_json::json!(2);
+        old.push(mock::person("p-zed", "Zed", 10, 1, None, None, 80));
+        let ivy = old.iter().position(|p| p["personGuid"] == "p-ivy").unwrap();
+        old.remove(ivy);
+        let dir = std::path::PathBuf::from(&client.env.working_path)
+            .join(crate::snapshots::SNAPSHOTS_DIR);
+        for (age, people) in [(2, old), (3, vec![])] {
+            let list = serde_json::json!({ "persons": people });
+            let taken = now - age * 24 * 60 * 60;
+            std::fs::write(dir.join(format!("{taken}.json")), list.to_string()).unwrap();
+        }
+
+        let (since, diff) = super::people_changes(&client, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(since.taken as i64, now - 2 * 24 * 60 * 60);
+        let guids = |people: &[crate::persons::Person]| {
+            people.iter().map(|p| p.guid.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(guids(&diff.new_referrals), ["p-ivy"]);
+        assert_eq!(guids(&diff.dropped), ["p-zed"]);
+        assert_eq!(diff.reassignments.len(), 1);
+        assert_eq!(diff.reassignments[0].person.guid, "p-ana");
+        assert_eq!(diff.status_changes.len(), 1);
+        assert_eq!(
+            diff.status_changes[0].to,
+            crate::persons::PersonStatus::Yellow
+        );
+    }
+
+    #[tokio::test]
+    async fn report_relogs_once_for_parallel_failures() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+        client.login().await.unwrap();
+
+        server.fail_next(mock::Route::Timeline, mock::Failure::ExpiredToken);
+        super::generate_report(&client, &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(server.logins(), 2);
+    }
+
+    #[tokio::test]
+    async fn report_survives_a_failed_timeline() {
+        let server = MockServer::with_fixtures().await;
+        let mut env = server.env();
+        env.concurrency = 1;
+        let client = super::ChurchClient::new(env).await.unwrap();
+        for _ in 0..3 {
+            server.fail_next(mock::Route::Timeline, mock::Failure::ServerError);
+        }
+
+        // Ana is fetched first and fails every try, everyone else still makes it
+        let report = super::generate_report(&client, &Default::default())
+            .await
+            .unwrap();
+        let north = report.get_pretty_zone(&mock::NORTH.0).unwrap();
+        assert!(!north.contains("Ana") && north.contains("Ben"));
+    }
+
+    #[tokio::test]
+    async fn average_groups_by_zone() {
+        let server = MockServer::with_fixtures().await;
+        let client = super::ChurchClient::new(server.env()).await.unwrap();
+
+        let average = super::get_average(&client, &Default::default(), &[])
+            .await
+            .unwrap();
+        // Fastest first, ties by name
+        assert!(average
+            .windows(2)
+            .all(|w| (w[0].1, &w[0].0) <= (w[1].1, &w[1].0)));
+        let average = average
+            .into_iter()
+            .collect::<std::collections::HashMap<String, usize>>();
+        assert_eq!(average.len(), 2);
+        assert!(average["North"] <= 120);
+        assert!(average["South"] <= 60);
+
+        // Contact times are remembered between runs
+        let timelines = server.hits(mock::Route::Timeline);
+        super::get_average(&client, &Default::default(), &[])
+            .await
+            .unwrap();
+        assert_eq!(server.hits(mock::Route::Timeline), timelines);
+    }
+
+    #[tokio::test]
+    async fn replays_a_recorded_run() {
+        let server = MockServer::with_fixtures().await;
+        let mut env = server.env();
+        env.cassette = crate::cassette::CassetteMode::Record;
+        let client = super::ChurchClient::new(env.clone()).await.unwrap();
+        let report = super::generate_report(&client, &Default::default())
+            .await
+            .unwrap();
+        let average = super::get_average(&client, &Default::default(), &[])
+            .await
+            .unwrap();
+
+        let cassette = std::path::Path::new(&env.working_path).join(crate::cassette::CASSETTE_FILE);
+        let recorded = std::fs::read_to_string(&cassette).unwrap();
+        assert!(!recorded.contains(mock::PASSWORD));
+        assert!(!recorded.contains(mock::USERNAME));
+        assert!(!recorded.contains("handle-1"));
+
+        // A fresh working path so nothing comes from the caches
+        let replay_dir = tempfile::tempdir().unwrap();
+        std::fs::copy(
+            &cassette,
+            replay_dir.path().join(crate::cassette::CASSETTE_FILE),
+        )
+        .unwrap();
+        env.working_path = replay_dir.path().to_string_lossy().to_string();
+        env.cassette = crate::cassette::CassetteMode::Replay;
+        let hits = server.hits(mock::Route::Timeline) + server.hits(mock::Route::People);
+
+        let client = super::ChurchClient::new(env).await.unwrap();
+        let replayed = super::generate_report(&client, &Default::default())
+            .await
+            .unwrap();
+        for zone in [mock::NORTH.0, mock::SOUTH.0] {
+            assert_eq!(
+                report.get_pretty_zone(&zone),
+                replayed.get_pretty_zone(&zone)
+            );
+        }
+        assert_eq!(report.entries.len(), replayed.entries.len());
+        assert_eq!(
+            super::get_average(&client, &Default::default(), &[])
+                .await
+                .unwrap(),
+            average
+        );
+        assert_eq!(
+            server.hits(mock::Route::Timeline) + server.hits(mock::Route::People),
+            hits
+        );
+        assert_eq!(server.logins(), 1);
+    }
+}
@@ -0,0 +1,635 @@
+// Jackson Coxson
+// A stand-in for referral manager and the Okta IDX login flow, so the church
+// client can be tested end to end without touching real mission data.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::Engine;
+use serde_json::{json, Value};
+
+use crate::{
+    env::{Endpoints, Env},
+    retry::RetryPolicy,
+};
+
+pub const MISSION_ID: usize = 42;
+pub const USERNAME: &str = "elder.test";
+pub const PASSWORD: &str = "hunter2";
+
+/// The endpoints the church client hits, used to inject failures and count requests
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Route {
+    LoginPage,
+    Introspect,
+    Identify,
+    Challenge,
+    Answer,
+    Success,
+    Auth,
+    People,
+    Timeline,
+}
+
+/// A failure to serve instead of the normal response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// Revokes the current session and bearer token before answering with a 401
+    ExpiredToken,
+    /// Answers with a 500 and an HTML body
+    ServerError,
+    /// Answers with a 200 and truncated JSON
+    MalformedJson,
+    /// Answers with a 429 asking to wait a second
+    RateLimited,
+    /// Answers with a 404
+    NotFound,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    base_url: String,
+    people: Vec<Value>,
+    timelines: HashMap<String, Vec<Value>>,
+    failures: HashMap<Route, VecDeque<Failure>>,
+    hits: HashMap<Route, usize>,
+    logins: usize,
+    session: Option<String>,
+    token: Option<String>,
+    token_lifetime: i64,
+    api_calls: usize,
+    /// Asks for a code from an authenticator app after the password when set
+    totp_secret: Option<String>,
+}
+
+impl MockState {
+    /// Counts the hit and pops the next queued failure for the route, if any
+    fn hit(&mut self, route: Route) -> Option<Failure> {
+        *self.hits.entry(route).or_default() += 1;
+        let failure = self.failures.get_mut(&route)?.pop_front()?;
+        if failure == Failure::ExpiredToken {
+            self.session = None;
+            self.token = None;
+        }
+        Some(failure)
+    }
+}
+
+type Shared = Arc<Mutex<MockState>>;
+
+pub struct MockServer {
+    state: Shared,
+    url: String,
+    working_dir: tempfile::TempDir,
+}
+
+impl MockServer {
+    /// Starts a server on a random local port with no people loaded
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState {
+            base_url: url.clone(),
+            token_lifetime: 60 * 60,
+            ..Default::default()
+        }));
+
+        let app = Router::new()
+            .route("/", get(login_page))
+            .route("/idp/idx/introspect", post(introspect))
+            .route("/idp/idx/identify", post(identify))
+            .route("/idp/idx/challenge", post(challenge))
+            .route("/idp/idx/challenge/answer", post(answer))
+            .route("/success", get(success))
+            .route("/services/auth", get(auth))
+            .route("/services/people/mission/{id}", get(people_list))
+            .route("/services/progress/timeline/{guid}", get(timeline))
+            .with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        Self {
+            state,
+            url,
+            working_dir: tempfile::tempdir().unwrap(),
+        }
+    }
+
+    /// Starts a server loaded with [fixture_people] and [fixture_timelines]
+    pub async fn with_fixtures() -> Self {
+        let server = Self::start().await;
+        for p in fixture_people() {
+            server.add_person(p);
+        }
+        for (guid, events) in fixture_timelines() {
+            server.set_timeline(guid, events);
+        }
+        server
+    }
+
+    /// Starts a server loaded with the committed corpus in ``fixtures/``
+    pub async fn with_corpus() -> Self {
+        let server = Self::start().await;
+        for p in corpus_people()["persons"].as_array().unwrap() {
+            server.add_person(p.clone());
+        }
+        for (guid, events) in corpus_timelines() {
+            server.set_timeline(&guid, events);
+        }
+        server
+    }
+
+    /// An environment pointing at this server with a fresh working directory
+    pub fn env(&self) -> Env {
+        Env {
+            church_username: USERNAME.to_string(),
+            church_password: PASSWORD.to_string(),
+            church_totp_secret: None,
+            working_path: self.working_dir.path().to_string_lossy().to_string(),
+            endpoints: Endpoints {
+                referral_manager: self.url.clone(),
+                church_id: self.url.clone(),
+            },
+            concurrency: crate::env::DEFAULT_CONCURRENCY,
+            timeline_cache_hours: crate::env::DEFAULT_TIMELINE_CACHE_HOURS,
+            // Real backoff would make the tests crawl
+            retry: RetryPolicy {
+                base_delay: std::time::Duration::from_millis(1),
+                max_delay: std::time::Duration::from_millis(10),
+                ..Default::default()
+            },
+            cassette: Default::default(),
+            collapse_duplicates: false,
+            report_filter: "".parse().unwrap(),
+            average_filter: "".parse().unwrap(),
+            policy: crate::policy::DEFAULT_POLICY.to_string(),
+            average_by: Default::default(),
+            report_order: Default::default(),
+        }
+    }
+
+    pub fn add_person(&self, person: Value) {
+        self.state.lock().unwrap().people.push(person);
+    }
+
+    /// Sets the timeline for a person, newest event first like referral manager
+    pub fn set_timeline(&self, guid: &str, events: Vec<Value>) {
+        self.state
+            .lock()
+            .unwrap()
+            .timelines
+            .insert(guid.to_string(), events);
+    }
+
+    /// Queues a failure for the next request to the route
+    pub fn fail_next(&self, route: Route, failure: Failure) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .entry(route)
+            .or_default()
+            .push_back(failure);
+    }
+
+    pub fn hits(&self, route: Route) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .hits
+            .get(&route)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets how many seconds the bearer tokens handed out from now on are good for
+    pub fn set_token_lifetime(&self, secs: i64) {
+        self.state.lock().unwrap().token_lifetime = secs;
+    }
+
+    /// Requires a TOTP code after the password, generated from this base32 secret
+    pub fn require_totp(&self, secret: &str) {
+        self.state.lock().unwrap().totp_secret = Some(secret.to_string());
+    }
+
+    /// How many times the full login flow completed
+    pub fn logins(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+}
+
+fn failure_response(failure: Failure) -> Response {
+    match failure {
+        Failure::ExpiredToken => (
+            StatusCode::UNAUTHORIZED,
+            "<html><body>Session expired</body></html>",
+        )
+            .into_response(),
+        Failure::ServerError => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "<html><body>Internal Server Error</body></html>",
+        )
+            .into_response(),
+        Failure::MalformedJson => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "application/json")],
+            "{\"persons\": [{\"personGuid\": ",
+        )
+            .into_response(),
+        Failure::RateLimited => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "1")],
+            "Too many requests",
+        )
+            .into_response(),
+        Failure::NotFound => (StatusCode::NOT_FOUND, "Not found").into_response(),
+    }
+}
+
+/// Builds an unsigned JWT carrying the claims referral manager hands out
+pub fn make_token(mission_id: usize, lifetime_secs: i64) -> String {
+    let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    let now = chrono::Utc::now().timestamp();
+    let header = engine.encode(json!({"alg": "none", "typ": "JWT"}).to_string());
+    let claims = engine.encode(
+        json!({
+            "missionId": mission_id,
+            "sub": USERNAME,
+            "aud": "referralmanager",
+            "iat": now,
+            "exp": now + lifetime_secs,
+        })
+        .to_string(),
+    );
+    format!("{header}.{claims}.signature")
+}
+
+async fn login_page(State(state): State<Shared>) -> Response {
+    if let Some(f) = state.lock().unwrap().hit(Route::LoginPage) {
+        return failure_response(f);
+    }
+    // Okta escapes the dashes in the state token, which the client has to undo
+    let page = "<html><script>var oktaData = {\"redirectUri\":\"x\",\"stateToken\":\"state\\x2Dtoken\",\"other\":1};</script></html>";
+    ([(header::CONTENT_TYPE, "text/html")], page).into_response()
+}
+
+async fn introspect(State(state): State<Shared>, Json(body): Json<Value>) -> Response {
+    if let Some(f) = state.lock().unwrap().hit(Route::Introspect) {
+        return failure_response(f);
+    }
+    if body["stateToken"] != "state-token" {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": "bad state"}))).into_response();
+    }
+    Json(json!({"stateHandle": "handle-1"})).into_response()
+}
+
+/// Okta echoes who is logging in with every step after identify
+fn challenge_response(state_handle: &str, authenticator: Value) -> Response {
+    Json(json!({
+        "stateHandle": state_handle,
+        "user": {"type": "object", "value": {
+            "id": "user-1",
+            "identifier": USERNAME,
+            "profile": {"login": USERNAME, "email": format!("{USERNAME}@example.com")},
+        }},
+        "remediation": {"value": [{"name": "challenge-authenticator"}]},
+        "currentAuthenticator": {"value": authenticator},
+    }))
+    .into_response()
+}
+
+fn idx_error(state_handle: &str, message: &str) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(json!({
+            "stateHandle": state_handle,
+            "messages": {"value": [{"message": message, "class": "ERROR"}]},
+        })),
+    )
+        .into_response()
+}
+
+fn password_authenticator() -> Value {
+    json!({"id": "aut-password", "type": "password", "displayName": "Password"})
+}
+
+fn totp_authenticator() -> Value {
+    json!({
+        "id": "aut-totp",
+        "type": "app",
+        "displayName": "Google Authenticator",
+        "methods": [{"type": "otp"}],
+    })
+}
+
+fn email_authenticator() -> Value {
+    json!({
+        "id": "aut-email",
+        "type": "email",
+        "displayName": "Email",
+        "methods": [{"type": "email"}],
+    })
+}
+
+async fn identify(State(state): State<Shared>, Json(body): Json<Value>) -> Response {
+    if let Some(f) = state.lock().unwrap().hit(Route::Identify) {
+        return failure_response(f);
+    }
+    if body["stateHandle"] != "handle-1" || body["identifier"] != USERNAME {
+        return (StatusCode::BAD_REQUEST, Json(json!({"error": "bad user"}))).into_response();
+    }
+    challenge_response("handle-2", password_authenticator())
+}
+
+async fn challenge(State(state): State<Shared>, Json(body): Json<Value>) -> Response {
+    if let Some(f) = state.lock().unwrap().hit(Route::Challenge) {
+        return failure_response(f);
+    }
+    if body["stateHandle"] != "handle-3" {
+        return idx_error("handle-3", "The session has expired");
+    }
+    match body["authenticator"]["id"].as_str() {
+        Some("aut-totp") => challenge_response("handle-4", totp_authenticator()),
+        Some("aut-email") => challenge_response("handle-4", email_authenticator()),
+        _ => idx_error("handle-3", "Unknown authenticator"),
+    }
+}
+
+async fn answer(State(state): State<Shared>, Json(body): Json<Value>) -> Response {
+    let mut state = state.lock().unwrap();
+    if let Some(f) = state.hit(Route::Answer) {
+        return failure_response(f);
+    }
+    let success =
+        Json(json!({"success": {"href": format!("{}/success", state.base_url)}})).into_response();
+    let passcode = body["credentials"]["passcode"].as_str().unwrap_or_default();
+    match body["stateHandle"].as_str() {
+        Some("handle-2") => {
+            if passcode != PASSWORD {
+                return idx_error("handle-2", "Password is incorrect");
+            }
+            if state.totp_secret.is_none() {
+                return success;
+            }
+            Json(json!({
+                "stateHandle": "handle-3",
+                "remediation": {"value": [{"name": "select-authenticator-authenticate"}]},
+                "authenticators": {"value": [totp_authenticator(), email_authenticator()]},
+            }))
+            .into_response()
+        }
+        Some("handle-4") => {
+            let totp = crate::idx::totp(state.totp_secret.as_deref().unwrap()).unwrap();
+            if !totp.check_current(passcode).unwrap() {
+                return idx_error("handle-4", "Invalid code. Try again.");
+            }
+            success
+        }
+        _ => idx_error("handle-2", "The session has expired"),
+    }
+}
+
+async fn success(State(state): State<Shared>) -> Response {
+    let mut state = state.lock().unwrap();
+    if let Some(f) = state.hit(Route::Success) {
+        return failure_response(f);
+    }
+    let session = format!("session-{}", state.logins);
+    state.session = Some(session.clone());
+    (
+        [(header::SET_COOKIE, format!("session={session}; Path=/"))],
+        "<html>Logged in</html>",
+    )
+        .into_response()
+}
+
+async fn auth(State(state): State<Shared>, headers: HeaderMap) -> Response {
+    let mut state = state.lock().unwrap();
+    if let Some(f) = state.hit(Route::Auth) {
+        return failure_response(f);
+    }
+    if !has_session(&state, &headers) {
+        return failure_response(Failure::ExpiredToken);
+    }
+    let token = make_token(MISSION_ID, state.token_lifetime);
+    state.token = Some(token.clone());
+    state.logins += 1;
+    Json(json!({"token": token})).into_response()
+}
+
+async fn people_list(
+    State(state): State<Shared>,
+    Path(id): Path<usize>,
+    headers: HeaderMap,
+) -> Response {
+    let mut state = state.lock().unwrap();
+    if let Some(f) = state.hit(Route::People) {
+        return failure_response(f);
+    }
+    if !has_session(&state, &headers) || !has_bearer(&state, &headers) {
+        return failure_response(Failure::ExpiredToken);
+    }
+    if id != MISSION_ID {
+        return (StatusCode::FORBIDDEN, "Wrong mission").into_response();
+    }
+    let people = json!({"persons": state.people});
+    api_response(&mut state, people)
+}
+
+async fn timeline(
+    State(state): State<Shared>,
+    Path(guid): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let mut state = state.lock().unwrap();
+    if let Some(f) = state.hit(Route::Timeline) {
+        return failure_response(f);
+    }
+    if !has_session(&state, &headers) || !has_bearer(&state, &headers) {
+        return failure_response(Failure::ExpiredToken);
+    }
+    let timeline = json!(state.timelines.get(&guid).cloned().unwrap_or_default());
+    api_response(&mut state, timeline)
+}
+
+/// Referral manager refreshes a cookie on every API call, which the client should keep
+fn api_response(state: &mut MockState, body: Value) -> Response {
+    state.api_calls += 1;
+    (
+        [(
+            header::SET_COOKIE,
+            format!("activity={}; Path=/", state.api_calls),
+        )],
+        Json(body),
+    )
+        .into_response()
+}
+
+fn has_bearer(state: &MockState, headers: &HeaderMap) -> bool {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    state.token.is_some() && bearer == state.token.as_deref()
+}
+
+fn has_session(state: &MockState, headers: &HeaderMap) -> bool {
+    let Some(session) = &state.session else {
+        return false;
+    };
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|c| c.to_str().ok())
+        .flat_map(|c| c.split(';'))
+        .any(|c| c.trim() == format!("session={session}"))
+}
+
+fn millis_ago(hours: i64) -> i64 {
+    (chrono::Utc::now() - chrono::Duration::hours(hours)).timestamp_millis()
+}
+
+/// A person as referral manager sends them
+pub fn person(
+    guid: &str,
+    first_name: &str,
+    referral_status: u8,
+    person_status: u8,
+    zone: Option<(usize, &str)>,
+    area_name: Option<&str>,
+    assigned_hours_ago: i64,
+) -> Value {
+    json!({
+        "personGuid": guid,
+        "firstName": first_name,
+        "referralStatusId": referral_status,
+        "personStatusId": person_status,
+        "missionId": MISSION_ID,
+        "zoneId": zone.map(|z| z.0),
+        "zoneName": zone.map(|z| z.1),
+        "districtId": zone.map(|z| z.0 * 10),
+        "districtName": zone.map(|z| format!("{} District", z.1)),
+        "areaName": area_name,
+        "referralAssignedDate": millis_ago(assigned_hours_ago),
+    })
+}
+
+/// Reads people the way they'd come from referral manager
+pub fn people(persons: &[Value]) -> Vec<crate::persons::Person> {
+    let list = json!({ "persons": persons });
+    crate::persons::Person::parse_lossy(list.to_string().as_bytes())
+        .unwrap()
+        .0
+}
+
+/// Adds the optional fields referral manager sometimes sends to a person
+pub fn with(mut person: Value, extra: Value) -> Value {
+    if let (Value::Object(person), Value::Object(extra)) = (&mut person, extra) {
+        person.extend(extra);
+    }
+    person
+}
+
+/// A timeline event as referral manager sends them
+pub fn event(item_type: &str, hours_ago: i64) -> Value {
+    json!({
+        "timelineItemType": item_type,
+        "itemDate": millis_ago(hours_ago),
+        "eventStatus": true,
+    })
+}
+
+pub const NORTH: (usize, &str) = (1, "North");
+pub const SOUTH: (usize, &str) = (2, "South");
+
+/// A small mission covering the cases the report and average care about
+pub fn fixture_people() -> Vec<Value> {
+    vec![
+        // Waiting three days with no contact
+        with(
+            person("p-ana", "Ana", 10, 1, Some(NORTH), Some("Alpha"), 72),
+            json!({
+                "lastName": "Lopez",
+                "phone": "+1 555 0100",
+                "preferredLanguage": "Spanish",
+                "gender": "F",
+                "ageCategory": "Young adult",
+                "referralSource": "Facebook",
+                "assignedMissionaries": ["Elder Smith", "Elder Jones"],
+            }),
+        ),
+        // Attempted but never reached
+        with(
+            person("p-ben", "Ben", 20, 1, Some(NORTH), Some("Alpha"), 60),
+            json!({"lastName": "Ng", "email": "ben@example.com"}),
+        ),
+        // Contacted recently, shouldn't be reported
+        person("p-cy", "Cy", 30, 2, Some(SOUTH), Some("Bravo"), 80),
+        // Contacted over two days ago, reported again
+        person("p-dee", "Dee", 20, 1, Some(SOUTH), Some("Bravo"), 100),
+        // Already a member
+        person("p-eve", "Eve", 20, 6, Some(SOUTH), Some("Bravo"), 100),
+        // Never assigned to a zone
+        with(
+            person("p-fay", "Fay", 10, 1, None, None, 50),
+            json!({"phone": "+1 555 0101"}),
+        ),
+        // Fresh referrals, inside the average window
+        person("p-gus", "Gus", 30, 2, Some(NORTH), Some("Alpha"), 10),
+        person("p-hal", "Hal", 30, 2, Some(SOUTH), Some("Bravo"), 5),
+        // A person status referral manager added after this was written
+        person("p-ivy", "Ivy", 10, 99, Some(NORTH), Some("Alpha"), 70),
+        // Tried and not reached, with a person status this version doesn't know
+        person("p-jo", "Jo", 20, 99, Some(SOUTH), Some("Bravo"), 80),
+        // Missing required fields, dropped by the lossy parse
+        json!({"personGuid": "p-bad", "firstName": "Broken"}),
+    ]
+}
+
+pub fn fixture_timelines() -> Vec<(&'static str, Vec<Value>)> {
+    vec![
+        ("p-ana", vec![event("NEW_REFERRAL", 72)]),
+        ("p-ben", vec![event("NEW_REFERRAL", 60)]),
+        (
+            "p-ivy",
+            vec![event("BAPTISMAL_GOAL", 65), event("NEW_REFERRAL", 70)],
+        ),
+        ("p-cy", vec![event("CONTACT", 3), event("NEW_REFERRAL", 80)]),
+        (
+            "p-dee",
+            vec![event("CONTACT", 90), event("NEW_REFERRAL", 100)],
+        ),
+        ("p-fay", vec![event("NEW_REFERRAL", 50)]),
+        (
+            "p-gus",
+            vec![event("TEACHING", 8), event("NEW_REFERRAL", 10)],
+        ),
+        ("p-hal", vec![event("CONTACT", 4), event("NEW_REFERRAL", 5)]),
+        (
+            "p-jo",
+            vec![event("CONTACT", 75), event("NEW_REFERRAL", 80)],
+        ),
+    ]
+}
+
+/// A redacted people list covering every status, with missing zones and areas,
+/// unknown statuses and records that can't be read
+pub fn corpus_people() -> Value {
+    serde_json::from_str(include_str!("../fixtures/people.json")).unwrap()
+}
+
+/// Redacted timelines from the corpus by person, covering every event type
+pub fn corpus_timelines() -> Vec<(String, Vec<Value>)> {
+    serde_json::from_str::<BTreeMap<String, Vec<Value>>>(include_str!("../fixtures/timelines.json"))
+        .unwrap()
+        .into_iter()
+        .collect()
+}
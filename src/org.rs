@@ -0,0 +1,332 @@
+// Jackson Coxson
+// How the mission is organized, from the mission down to areas. Referral manager
+// doesn't have an org endpoint we can use, so this is pieced together from the people list.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::persons::Person;
+
+/// How far down the mission to group people
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Mission,
+    #[default]
+    Zone,
+    District,
+    Area,
+}
+
+impl Level {
+    /// Reads ``AVERAGE_BY``, which can be ``mission``, ``zone``, ``district`` or ``area``
+    pub fn from_vars() -> anyhow::Result<Self> {
+        match std::env::var("AVERAGE_BY") {
+            Ok(level) => level
+                .parse()
+                .map_err(|e| anyhow::anyhow!("AVERAGE_BY isn't valid: {e}")),
+            Err(_) => Ok(Self::Zone),
+        }
+    }
+
+    /// The unit a person belongs to at this level, if they've been assigned that far down
+    pub fn unit_of(&self, person: &Person) -> Option<OrgUnit> {
+        match self {
+            Self::Mission => Some(OrgUnit::Mission),
+            Self::Zone => person.zone_id.map(OrgUnit::Zone),
+            Self::District => person.district_id.map(OrgUnit::District),
+            Self::Area => Some(OrgUnit::Area(person.zone_id?, person.area_name.clone()?)),
+        }
+    }
+
+    /// The name of the unit a person belongs to at this level
+    pub fn label(&self, person: &Person) -> Option<String> {
+        match self {
+            Self::Mission => Some("Mission".to_string()),
+            Self::Zone => person
+                .zone_name
+                .clone()
+                .or_else(|| person.zone_id.map(|z| z.to_string())),
+            Self::District => person.district_id.map(|d| {
+                person
+                    .district_name
+                    .clone()
+                    .unwrap_or_else(|| format!("District {d}"))
+            }),
+            // Area names can repeat across zones, so the zone keeps them apart
+            Self::Area => Some(format!(
+                "{} ({})",
+                person.area_name.as_ref()?,
+                Self::Zone.label(person)?
+            )),
+        }
+    }
+}
+
+impl std::str::FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mission" => Ok(Self::Mission),
+            "zone" => Ok(Self::Zone),
+            "district" => Ok(Self::District),
+            "area" => Ok(Self::Area),
+            _ => Err(format!(
+                "unknown level {s}, use mission, zone, district or area"
+            )),
+        }
+    }
+}
+
+/// One part of the mission, written as ``mission``, ``zone:1``, ``district:10`` or ``area:1:Alpha``
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum OrgUnit {
+    Mission,
+    Zone(usize),
+    District(usize),
+    /// An area by zone ID and name, since area names can repeat across zones
+    Area(usize, String),
+}
+
+impl OrgUnit {
+    pub fn level(&self) -> Level {
+        match self {
+            Self::Mission => Level::Mission,
+            Self::Zone(_) => Level::Zone,
+            Self::District(_) => Level::District,
+            Self::Area(..) => Level::Area,
+        }
+    }
+
+    pub fn contains(&self, person: &Person) -> bool {
+        self.level().unit_of(person).as_ref() == Some(self)
+    }
+}
+
+impl std::str::FromStr for OrgUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("{s} isn't a mission, zone:ID, district:ID or area:ZONE:NAME");
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("mission") => Ok(Self::Mission),
+            Some((level, id)) => match level.to_lowercase().as_str() {
+                "zone" => Ok(Self::Zone(id.parse().map_err(|_| bad())?)),
+                "district" => Ok(Self::District(id.parse().map_err(|_| bad())?)),
+                "area" => match id.split_once(':') {
+                    Some((zone, name)) if !name.is_empty() => Ok(Self::Area(
+                        zone.parse().map_err(|_| bad())?,
+                        name.to_string(),
+                    )),
+                    _ => Err(bad()),
+                },
+                _ => Err(bad()),
+            },
+            None => Err(bad()),
+        }
+    }
+}
+
+impl TryFrom<String> for OrgUnit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<OrgUnit> for String {
+    fn from(unit: OrgUnit) -> Self {
+        unit.to_string()
+    }
+}
+
+impl std::fmt::Display for OrgUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mission => write!(f, "mission"),
+            Self::Zone(id) => write!(f, "zone:{id}"),
+            Self::District(id) => write!(f, "district:{id}"),
+            Self::Area(zone, name) => write!(f, "area:{zone}:{name}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mission {
+    pub zones: BTreeMap<usize, Zone>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub id: usize,
+    pub name: String,
+    pub districts: BTreeMap<usize, District>,
+    /// Areas of people who have a zone but no district
+    pub loose_areas: BTreeSet<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct District {
+    pub id: usize,
+    pub name: String,
+    pub areas: BTreeSet<String>,
+}
+
+impl Mission {
+    /// Pieces the mission together from everyone's assignments
+    pub fn from_people<'a>(people: impl IntoIterator<Item = &'a Person>) -> Self {
+        let mut mission = Self::default();
+        for person in people {
+            let Some(zone_id) = person.zone_id else {
+                continue;
+            };
+            let zone = mission.zones.entry(zone_id).or_insert_with(|| Zone {
+                id: zone_id,
+                name: zone_id.to_string(),
+                districts: BTreeMap::new(),
+                loose_areas: BTreeSet::new(),
+            });
+            if let Some(name) = &person.zone_name {
+                zone.name = name.clone();
+            }
+
+            match person.district_id {
+                Some(district_id) => {
+                    let district = zone
+                        .districts
+                        .entry(district_id)
+                        .or_insert_with(|| District {
+                            id: district_id,
+                            name: format!("District {district_id}"),
+                            areas: BTreeSet::new(),
+                        });
+                    if let Some(name) = &person.district_name {
+                        district.name = name.clone();
+                    }
+                    if let Some(area) = &person.area_name {
+                        district.areas.insert(area.clone());
+                    }
+                }
+                None => {
+                    if let Some(area) = &person.area_name {
+                        zone.loose_areas.insert(area.clone());
+                    }
+                }
+            }
+        }
+        mission
+    }
+
+    pub fn district(&self, id: usize) -> Option<&District> {
+        self.zones.values().find_map(|z| z.districts.get(&id))
+    }
+
+    /// Every unit at a level, in order
+    pub fn units(&self, level: Level) -> Vec<OrgUnit> {
+        match level {
+            Level::Mission => vec![OrgUnit::Mission],
+            Level::Zone => self.zones.keys().copied().map(OrgUnit::Zone).collect(),
+            Level::District => self
+                .zones
+                .values()
+                .flat_map(|z| z.districts.keys().copied().map(OrgUnit::District))
+                .collect(),
+            Level::Area => self
+                .zones
+                .values()
+                .flat_map(|z| {
+                    z.districts
+                        .values()
+                        .flat_map(|d| d.areas.iter())
+                        .chain(z.loose_areas.iter())
+                        .map(|a| OrgUnit::Area(z.id, a.clone()))
+                })
+                .collect(),
+        }
+    }
+
+    /// What to call a unit when showing it to people
+    pub fn name_of(&self, unit: &OrgUnit) -> String {
+        match unit {
+            OrgUnit::Mission => "Mission".to_string(),
+            OrgUnit::Zone(id) => self
+                .zones
+                .get(id)
+                .map(|z| z.name.clone())
+                .unwrap_or_else(|| id.to_string()),
+            OrgUnit::District(id) => self
+                .district(*id)
+                .map(|d| d.name.clone())
+                .unwrap_or_else(|| format!("District {id}")),
+            OrgUnit::Area(zone, name) => {
+                format!("{name} ({})", self.name_of(&OrgUnit::Zone(*zone)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Level, Mission, OrgUnit};
+    use crate::mock::{people, person, with, NORTH, SOUTH};
+
+    #[test]
+    fn mission_from_people() {
+        let people = people(&[
+            person("p-1", "Ana", 10, 1, Some(NORTH), Some("Alpha"), 1),
+            person("p-2", "Ben", 10, 1, Some(NORTH), Some("Beta"), 1),
+            with(
+                person("p-3", "Cy", 10, 1, Some(SOUTH), Some("Bravo"), 1),
+                serde_json::json!({"districtId": null}),
+            ),
+            person("p-4", "Di", 10, 1, None, None, 1),
+            with(
+                person("p-5", "Eve", 10, 1, Some(SOUTH), Some("Alpha"), 1),
+                serde_json::json!({"districtId": null}),
+            ),
+        ]);
+        let mission = Mission::from_people(&people);
+
+        assert_eq!(mission.zones.len(), 2);
+        let north = &mission.zones[&NORTH.0];
+        assert_eq!(north.name, "North");
+        assert_eq!(north.districts[&10].name, "North District");
+        assert_eq!(
+            north.districts[&10].areas.iter().collect::<Vec<_>>(),
+            ["Alpha", "Beta"]
+        );
+        assert!(mission.zones[&SOUTH.0].districts.is_empty());
+        assert_eq!(
+            mission.units(Level::Area),
+            [
+                OrgUnit::Area(NORTH.0, "Alpha".to_string()),
+                OrgUnit::Area(NORTH.0, "Beta".to_string()),
+                OrgUnit::Area(SOUTH.0, "Alpha".to_string()),
+                OrgUnit::Area(SOUTH.0, "Bravo".to_string())
+            ]
+        );
+        // Areas with the same name in different zones are kept apart
+        assert!(!OrgUnit::Area(NORTH.0, "Alpha".to_string()).contains(&people[4]));
+        assert_eq!(Level::Area.label(&people[0]).unwrap(), "Alpha (North)");
+        assert_eq!(Level::Area.label(&people[4]).unwrap(), "Alpha (South)");
+        assert_eq!(mission.units(Level::District), [OrgUnit::District(10)]);
+        assert_eq!(mission.name_of(&OrgUnit::District(10)), "North District");
+        assert_eq!(mission.name_of(&OrgUnit::District(99)), "District 99");
+
+        assert!(OrgUnit::District(10).contains(&people[1]));
+        assert!(!OrgUnit::Zone(NORTH.0).contains(&people[2]));
+        assert!(OrgUnit::Mission.contains(&people[3]));
+        assert_eq!(Level::District.label(&people[0]).unwrap(), "North District");
+        assert_eq!(Level::District.label(&people[2]), None);
+
+        for unit in ["mission", "zone:1", "district:10", "area:2:Bravo Two"] {
+            assert_eq!(unit.parse::<OrgUnit>().unwrap().to_string(), unit);
+        }
+        assert!("zone:North".parse::<OrgUnit>().is_err());
+        assert!("stake:1".parse::<OrgUnit>().is_err());
+        assert!("area:Bravo".parse::<OrgUnit>().is_err());
+    }
+}
@@ -1,16 +1,146 @@
 // Jackson Coxson
 
+use std::{collections::BTreeMap, marker::PhantomData};
+
 use chrono::naive::serde::ts_milliseconds;
 use chrono::NaiveDateTime;
 use log::warn;
-use serde::{Deserialize, Serialize};
-use serde_repr::{Deserialize_repr, Serialize_repr};
+use serde::{
+    de::{DeserializeOwned, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::{value::RawValue, Value};
+use serde_with::{serde_as, DefaultOnError};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Persons {
-    persons: Vec<Person>,
+/// The shape of the people list response from referral manager, and of the cached copies.
+/// The response body is read whole, but people are parsed straight out of it one at a time,
+/// so it's never built into a tree of JSON values.
+#[derive(Debug, Default, Deserialize)]
+pub struct PeopleList {
+    #[serde(default)]
+    persons: LossyList<Person>,
+    /// Only in cached lists, what parsing the original response found
+    #[serde(default)]
+    diagnostics: Option<ParseDiagnostics>,
+}
+
+impl PeopleList {
+    pub fn into_parts(self) -> (Vec<Person>, ParseDiagnostics) {
+        let diagnostics = self.diagnostics.unwrap_or(self.persons.diagnostics);
+        (self.persons.items, diagnostics)
+    }
+}
+
+/// What gets written to the people list cache
+#[derive(Serialize)]
+pub struct CachedPeopleList<'a> {
+    pub persons: &'a [Person],
+    pub diagnostics: &'a ParseDiagnostics,
+}
+
+/// Something that comes in a list from referral manager and can be skipped if it's broken
+pub trait LossyRecord: DeserializeOwned {
+    /// What to call one in the logs
+    const NAME: &'static str;
+
+    /// Whether it has a status or type this version doesn't know
+    fn is_unknown(&self) -> bool;
+}
+
+/// A JSON list parsed one record at a time. Records that fail are counted in the
+/// diagnostics and skipped, instead of failing the list or being parsed twice. A null
+/// list is an empty one.
+#[derive(Debug)]
+pub struct LossyList<T> {
+    pub items: Vec<T>,
+    pub diagnostics: ParseDiagnostics,
+}
+
+impl<T> Default for LossyList<T> {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            diagnostics: ParseDiagnostics::default(),
+        }
+    }
+}
+
+impl<'de, T: LossyRecord> Deserialize<'de> for LossyList<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ListVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: LossyRecord> Visitor<'de> for ListVisitor<T> {
+            type Value = LossyList<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a list of {}s", T::NAME)
+            }
+
+            fn visit_none<E>(self) -> Result<Self::Value, E> {
+                Ok(LossyList::default())
+            }
+
+            fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<Self::Value, D::Error> {
+                d.deserialize_seq(self)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut list = LossyList::default();
+                if let Some(len) = seq.size_hint() {
+                    list.items.reserve(len);
+                }
+                // Each record is only held as text until it's parsed
+                while let Some(raw) = seq.next_element::<Box<RawValue>>()? {
+                    list.push(&raw);
+                }
+                if list.diagnostics.unknown > 0 {
+                    warn!(
+                        "{} {}s have values this version doesn't know about",
+                        list.diagnostics.unknown,
+                        T::NAME
+                    );
+                }
+                Ok(list)
+            }
+        }
+
+        deserializer.deserialize_option(ListVisitor(PhantomData))
+    }
+}
+
+impl<T: LossyRecord> LossyList<T> {
+    fn push(&mut self, raw: &RawValue) {
+        self.diagnostics.total += 1;
+        let mut deserializer = serde_json::Deserializer::from_str(raw.get());
+        match serde_path_to_error::deserialize::<_, T>(&mut deserializer) {
+            Ok(r) => {
+                if r.is_unknown() {
+                    self.diagnostics.unknown += 1;
+                }
+                self.items.push(r);
+            }
+            Err(e) => {
+                let field = broken_field(&e);
+                warn!("Unable to parse a {}, {field} is broken", T::NAME);
+                self.diagnostics.failed += 1;
+                *self.diagnostics.broken_fields.entry(field).or_default() += 1;
+                if self.diagnostics.sample.is_none() {
+                    self.diagnostics.sample = serde_json::from_str(raw.get())
+                        .ok()
+                        .map(|v| redact_values(&v));
+                }
+            }
+        }
+    }
+
+    pub fn into_parts(self) -> (Vec<T>, ParseDiagnostics) {
+        (self.items, self.diagnostics)
+    }
 }
 
+/// The fields past the assigned date aren't always sent, and their shapes are less
+/// settled, so a missing or strange one is left empty instead of dropping the person.
+#[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Person {
     #[serde(rename = "personGuid")]
@@ -43,117 +173,1053 @@ pub struct Person {
     #[serde(rename = "referralAssignedDate")]
     #[serde(with = "ts_milliseconds")]
     pub assigned_date: NaiveDateTime,
+
+    #[serde(rename = "lastName", default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub last_name: Option<String>,
+
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub phone: Option<String>,
+
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub email: Option<String>,
+
+    #[serde(rename = "preferredLanguage", default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub preferred_language: Option<String>,
+
+    #[serde(default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub gender: Option<Gender>,
+
+    #[serde(rename = "ageCategory", default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub age_group: Option<String>,
+
+    /// Where the referral came from, like an ad or a member
+    #[serde(rename = "referralSource", default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub referral_source: Option<String>,
+
+    /// Names of the missionaries covering the area
+    #[serde(rename = "assignedMissionaries", default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub assigned_missionaries: Vec<String>,
+
+    /// Districts are otherwise only known by ID
+    #[serde(rename = "districtName", default)]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    pub district_name: Option<String>,
 }
 
 impl Person {
-    pub fn parse_lossy(mut object: serde_json::Value) -> Vec<Self> {
-        if let serde_json::Value::Array(persons) = object["persons"].take() {
-            let mut res: Vec<Self> = Vec::with_capacity(persons.len());
-            for person in persons {
-                if let Ok(p) = serde_json::from_value(person.clone()) {
-                    res.push(p);
-                } else {
-                    warn!("Unable to parse person: {person:?}");
-                }
-            }
-            res
-        } else {
-            Vec::new()
+    pub fn full_name(&self) -> String {
+        match &self.last_name {
+            Some(last) if !last.is_empty() => format!("{} {last}", self.first_name),
+            _ => self.first_name.clone(),
         }
     }
+
+    pub fn has_phone(&self) -> bool {
+        self.phone.as_ref().is_some_and(|p| !p.trim().is_empty())
+    }
+
+    pub fn has_email(&self) -> bool {
+        self.email.as_ref().is_some_and(|e| !e.trim().is_empty())
+    }
+
+    /// What missionaries want to know before contacting, for the parentheses of a report line.
+    /// Phone numbers and emails themselves are left out since reports get shared in chats.
+    pub fn report_details(&self) -> Vec<String> {
+        let mut details = Vec::new();
+        if let Some(gender) = &self.gender {
+            details.push(gender.to_string());
+        }
+        if let Some(age) = &self.age_group {
+            details.push(age.clone());
+        }
+        if let Some(language) = &self.preferred_language {
+            details.push(language.clone());
+        }
+        if let Some(source) = &self.referral_source {
+            details.push(format!("via {source}"));
+        }
+        if !self.has_phone() && !self.has_email() {
+            details.push("no phone or email".to_string());
+        }
+        details
+    }
+
+    /// Whether referral manager gave this person a status this version doesn't know
+    pub fn has_unknown_status(&self) -> bool {
+        matches!(self.referral_status, ReferralStatus::Unknown(_))
+            || matches!(self.person_status, PersonStatus::Unknown(_))
+    }
+
+    /// Parses a people list, skipping anyone who can't be read
+    pub fn parse_lossy(json: &[u8]) -> Result<(Vec<Self>, ParseDiagnostics), serde_json::Error> {
+        Ok(serde_json::from_slice::<PeopleList>(json)?.into_parts())
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl LossyRecord for Person {
+    const NAME: &'static str = "person";
+
+    fn is_unknown(&self) -> bool {
+        self.has_unknown_status()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "RawTimelineEvent", into = "RawTimelineEvent")]
 pub struct TimelineEvent {
-    #[serde(rename = "timelineItemType")]
     pub item_type: TimelineItemType,
+    pub item_date: NaiveDateTime,
+    pub status: Option<bool>,
+    /// The missionary who recorded the event
+    pub recorded_by: Option<String>,
+    /// The area of the companionship that did the work
+    pub area_name: Option<String>,
+    pub details: EventDetails,
+}
+
+/// What an event carries beyond the basics, which depends on its type
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventDetails {
+    /// A contact attempt, whether or not it reached the person
+    Contact {
+        method: Option<ContactMethod>,
+    },
+    Teaching {
+        lesson: Option<String>,
+    },
+    Note {
+        text: Option<String>,
+    },
+    Task {
+        description: Option<String>,
+        due: Option<NaiveDateTime>,
+    },
+    /// Types that don't carry anything else worth keeping
+    None,
+}
+
+/// How missionaries tried to reach someone
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContactMethod {
+    Text,
+    Call,
+    Knock,
+    Email,
+    InPerson,
+    SocialMedia,
+    Unknown(String),
+}
+
+impl From<String> for ContactMethod {
+    fn from(method: String) -> Self {
+        match method.as_str() {
+            "TEXT" => Self::Text,
+            "CALL" => Self::Call,
+            "KNOCK" => Self::Knock,
+            "EMAIL" => Self::Email,
+            "IN_PERSON" => Self::InPerson,
+            "SOCIAL_MEDIA" => Self::SocialMedia,
+            _ => Self::Unknown(method),
+        }
+    }
+}
+
+impl From<ContactMethod> for String {
+    fn from(method: ContactMethod) -> Self {
+        match method {
+            ContactMethod::Text => "TEXT",
+            ContactMethod::Call => "CALL",
+            ContactMethod::Knock => "KNOCK",
+            ContactMethod::Email => "EMAIL",
+            ContactMethod::InPerson => "IN_PERSON",
+            ContactMethod::SocialMedia => "SOCIAL_MEDIA",
+            ContactMethod::Unknown(method) => return method,
+        }
+        .to_string()
+    }
+}
+
+/// A timeline event the way referral manager sends it, with every type's fields side by side.
+/// The extra fields are lenient like [Person]'s, so an odd one doesn't cost the event.
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RawTimelineEvent {
+    #[serde(rename = "timelineItemType")]
+    item_type: TimelineItemType,
 
     #[serde(rename = "itemDate")]
     #[serde(with = "ts_milliseconds")]
-    pub item_date: NaiveDateTime,
+    item_date: NaiveDateTime,
 
     #[serde(rename = "eventStatus")]
-    pub status: Option<bool>,
+    status: Option<bool>,
+
+    #[serde(
+        rename = "recordedBy",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    recorded_by: Option<String>,
+
+    #[serde(rename = "areaName", default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    area_name: Option<String>,
+
+    #[serde(
+        rename = "contactType",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    contact_type: Option<String>,
+
+    #[serde(
+        rename = "lessonName",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    lesson: Option<String>,
+
+    #[serde(rename = "note", default, skip_serializing_if = "Option::is_none")]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    note: Option<String>,
+
+    #[serde(
+        rename = "taskDescription",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    task_description: Option<String>,
+
+    /// Unix milliseconds
+    #[serde(
+        rename = "taskDueDate",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[serde_as(deserialize_as = "DefaultOnError")]
+    task_due: Option<i64>,
+}
+
+impl From<RawTimelineEvent> for TimelineEvent {
+    fn from(raw: RawTimelineEvent) -> Self {
+        let details = match raw.item_type {
+            TimelineItemType::Contact => EventDetails::Contact {
+                method: raw.contact_type.map(ContactMethod::from),
+            },
+            TimelineItemType::Teaching => EventDetails::Teaching { lesson: raw.lesson },
+            TimelineItemType::Note => EventDetails::Note { text: raw.note },
+            TimelineItemType::Task => EventDetails::Task {
+                description: raw.task_description,
+                due: raw
+                    .task_due
+                    .and_then(chrono::DateTime::from_timestamp_millis)
+                    .map(|d| d.naive_utc()),
+            },
+            _ => EventDetails::None,
+        };
+        Self {
+            item_type: raw.item_type,
+            item_date: raw.item_date,
+            status: raw.status,
+            recorded_by: raw.recorded_by,
+            area_name: raw.area_name,
+            details,
+        }
+    }
+}
+
+impl From<TimelineEvent> for RawTimelineEvent {
+    fn from(event: TimelineEvent) -> Self {
+        let mut raw = Self {
+            item_type: event.item_type,
+            item_date: event.item_date,
+            status: event.status,
+            recorded_by: event.recorded_by,
+            area_name: event.area_name,
+            contact_type: None,
+            lesson: None,
+            note: None,
+            task_description: None,
+            task_due: None,
+        };
+        match event.details {
+            EventDetails::Contact { method } => raw.contact_type = method.map(String::from),
+            EventDetails::Teaching { lesson } => raw.lesson = lesson,
+            EventDetails::Note { text } => raw.note = text,
+            EventDetails::Task { description, due } => {
+                raw.task_description = description;
+                raw.task_due = due.map(|d| d.and_utc().timestamp_millis());
+            }
+            EventDetails::None => {}
+        }
+        raw
+    }
 }
 
 impl TimelineEvent {
-    pub fn parse_lossy(object: serde_json::Value) -> Vec<Self> {
-        if let serde_json::Value::Array(persons) = object {
-            let mut res: Vec<Self> = Vec::with_capacity(persons.len());
-            for person in persons {
-                if let Ok(p) = serde_json::from_value(person.clone()) {
-                    res.push(p);
-                } else {
-                    warn!("Unable to parse timeline event: {person:?}");
-                }
+    /// Parses a timeline, skipping events that can't be read
+    pub fn parse_lossy(json: &[u8]) -> Result<(Vec<Self>, ParseDiagnostics), serde_json::Error> {
+        Ok(serde_json::from_slice::<LossyList<Self>>(json)?.into_parts())
+    }
+}
+
+impl LossyRecord for TimelineEvent {
+    const NAME: &'static str = "timeline event";
+
+    fn is_unknown(&self) -> bool {
+        matches!(self.item_type, TimelineItemType::Unknown(_))
+    }
+}
+
+/// How much share of failed records is worth warning about in a report
+pub const PARSE_WARNING_PERCENT: usize = 5;
+
+/// What happened while parsing a list of records from referral manager.
+/// Records hold names and contact info, so nothing here keeps their values.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParseDiagnostics {
+    pub total: usize,
+    pub failed: usize,
+    /// Records that were kept but have a status or type this version doesn't know
+    pub unknown: usize,
+    /// How many records each field broke
+    pub broken_fields: BTreeMap<String, usize>,
+    /// The shape of the first record that failed, with its values replaced by their types
+    pub sample: Option<Value>,
+}
+
+impl ParseDiagnostics {
+    pub fn merge(&mut self, other: &Self) {
+        self.total += other.total;
+        self.failed += other.failed;
+        self.unknown += other.unknown;
+        for (field, count) in &other.broken_fields {
+            *self.broken_fields.entry(field.clone()).or_default() += count;
+        }
+        if self.sample.is_none() {
+            self.sample = other.sample.clone();
+        }
+    }
+
+    /// Whether enough records failed that the results can't be trusted
+    pub fn is_concerning(&self) -> bool {
+        self.failed > 0 && self.failed * 100 >= self.total * PARSE_WARNING_PERCENT
+    }
+}
+
+impl std::fmt::Display for ParseDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Parsed {} of {} records, {} failed and {} have values this version doesn't know",
+            self.total - self.failed,
+            self.total,
+            self.failed,
+            self.unknown
+        )?;
+        if !self.broken_fields.is_empty() {
+            write!(f, "\nBroken fields:")?;
+            for (field, count) in &self.broken_fields {
+                write!(f, "\n  {field}: {count}")?;
             }
-            res
-        } else {
-            Vec::new()
         }
+        if let Some(sample) = &self.sample {
+            write!(
+                f,
+                "\nA failed record, with its values hidden:\n{}",
+                serde_json::to_string_pretty(sample).unwrap_or_default()
+            )?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Debug)]
-#[repr(u8)]
+/// The field a parse error is about. Missing fields are reported on the record itself,
+/// so their name has to come out of the message.
+fn broken_field(e: &serde_path_to_error::Error<serde_json::Error>) -> String {
+    let message = e.inner().to_string();
+    if let Some(field) = message
+        .strip_prefix("missing field `")
+        .and_then(|m| m.split('`').next())
+    {
+        return field.to_string();
+    }
+    match e.path().to_string().as_str() {
+        "." => "the record".to_string(),
+        path => path.to_string(),
+    }
+}
+
+/// Replaces every value with its type, keeping the keys so the shape can be seen
+fn redact_values(value: &Value) -> Value {
+    match value {
+        Value::String(_) => Value::String("<string>".to_string()),
+        Value::Number(_) => Value::String("<number>".to_string()),
+        Value::Bool(_) => Value::String("<bool>".to_string()),
+        Value::Null => Value::Null,
+        Value::Array(values) => Value::Array(values.iter().map(redact_values).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), redact_values(v)))
+                .collect(),
+        ),
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Gender {
+    #[serde(rename = "M")]
+    Male,
+    #[serde(rename = "F")]
+    Female,
+}
+
+impl std::fmt::Display for Gender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Male => write!(f, "Male"),
+            Self::Female => write!(f, "Female"),
+        }
+    }
+}
+
+/// Referral manager adds statuses from time to time. Ones this version doesn't know
+/// come through as [ReferralStatus::Unknown] so the person isn't dropped.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
 pub enum ReferralStatus {
-    NotAttempted = 10,
-    NotSuccessful = 20,
-    Successful = 30,
+    NotAttempted,
+    NotSuccessful,
+    Successful,
+    Unknown(u16),
 }
 
-#[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Debug, Eq, PartialOrd, Ord)]
-#[repr(u8)]
+impl From<u16> for ReferralStatus {
+    fn from(id: u16) -> Self {
+        match id {
+            10 => Self::NotAttempted,
+            20 => Self::NotSuccessful,
+            30 => Self::Successful,
+            id => Self::Unknown(id),
+        }
+    }
+}
+
+impl From<ReferralStatus> for u16 {
+    fn from(status: ReferralStatus) -> Self {
+        match status {
+            ReferralStatus::NotAttempted => 10,
+            ReferralStatus::NotSuccessful => 20,
+            ReferralStatus::Successful => 30,
+            ReferralStatus::Unknown(id) => id,
+        }
+    }
+}
+
+/// Reads a status by its name, ignoring case, or by its ID
+impl std::str::FromStr for ReferralStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse::<u16>() {
+            return Ok(id.into());
+        }
+        match s.to_lowercase().as_str() {
+            "notattempted" => Ok(Self::NotAttempted),
+            "notsuccessful" => Ok(Self::NotSuccessful),
+            "successful" => Ok(Self::Successful),
+            _ => Err(format!("unknown referral status {s}")),
+        }
+    }
+}
+
+/// Written as its name, or its ID if this version doesn't know it, so it reads back the same
+impl std::fmt::Display for ReferralStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown(id) => write!(f, "{id}"),
+            status => write!(f, "{status:?}"),
+        }
+    }
+}
+
+/// Ordered by ID like referral manager, so an unknown status still sorts near its neighbors
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(from = "u16", into = "u16")]
 pub enum PersonStatus {
-    Yellow = 1,
-    Green = 2,
-    BetterGreen = 3,
-    ProgressingGreen = 4,
-    NewMember = 6,
-    NotInterested = 20,
-    NotInterestedDeclared = 21,
-    NotProgressing = 22,
-    UnableToContact = 23,
-    Prank = 25, // unsure
-    NotRecentlyContacted = 26,
-    TooBusy = 27,
-    OutsideAreaStrength = 28,
-    Member = 40,
-    Moved = 201,
-}
-
-#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+    Yellow,
+    Green,
+    BetterGreen,
+    ProgressingGreen,
+    NewMember,
+    NotInterested,
+    NotInterestedDeclared,
+    NotProgressing,
+    UnableToContact,
+    Prank, // unsure
+    NotRecentlyContacted,
+    TooBusy,
+    OutsideAreaStrength,
+    Member,
+    Moved,
+    Unknown(u16),
+}
+
+impl From<u16> for PersonStatus {
+    fn from(id: u16) -> Self {
+        match id {
+            1 => Self::Yellow,
+            2 => Self::Green,
+            3 => Self::BetterGreen,
+            4 => Self::ProgressingGreen,
+            6 => Self::NewMember,
+            20 => Self::NotInterested,
+            21 => Self::NotInterestedDeclared,
+            22 => Self::NotProgressing,
+            23 => Self::UnableToContact,
+            25 => Self::Prank,
+            26 => Self::NotRecentlyContacted,
+            27 => Self::TooBusy,
+            28 => Self::OutsideAreaStrength,
+            40 => Self::Member,
+            201 => Self::Moved,
+            id => Self::Unknown(id),
+        }
+    }
+}
+
+impl From<PersonStatus> for u16 {
+    fn from(status: PersonStatus) -> Self {
+        match status {
+            PersonStatus::Yellow => 1,
+            PersonStatus::Green => 2,
+            PersonStatus::BetterGreen => 3,
+            PersonStatus::ProgressingGreen => 4,
+            PersonStatus::NewMember => 6,
+            PersonStatus::NotInterested => 20,
+            PersonStatus::NotInterestedDeclared => 21,
+            PersonStatus::NotProgressing => 22,
+            PersonStatus::UnableToContact => 23,
+            PersonStatus::Prank => 25,
+            PersonStatus::NotRecentlyContacted => 26,
+            PersonStatus::TooBusy => 27,
+            PersonStatus::OutsideAreaStrength => 28,
+            PersonStatus::Member => 40,
+            PersonStatus::Moved => 201,
+            PersonStatus::Unknown(id) => id,
+        }
+    }
+}
+
+/// Reads a status by its name, ignoring case, or by its ID
+impl std::str::FromStr for PersonStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse::<u16>() {
+            return Ok(id.into());
+        }
+        match s.to_lowercase().as_str() {
+            "yellow" => Ok(Self::Yellow),
+            "green" => Ok(Self::Green),
+            "bettergreen" => Ok(Self::BetterGreen),
+            "progressinggreen" => Ok(Self::ProgressingGreen),
+            "newmember" => Ok(Self::NewMember),
+            "notinterested" => Ok(Self::NotInterested),
+            "notinteresteddeclared" => Ok(Self::NotInterestedDeclared),
+            "notprogressing" => Ok(Self::NotProgressing),
+            "unabletocontact" => Ok(Self::UnableToContact),
+            "prank" => Ok(Self::Prank),
+            "notrecentlycontacted" => Ok(Self::NotRecentlyContacted),
+            "toobusy" => Ok(Self::TooBusy),
+            "outsideareastrength" => Ok(Self::OutsideAreaStrength),
+            "member" => Ok(Self::Member),
+            "moved" => Ok(Self::Moved),
+            _ => Err(format!("unknown person status {s}")),
+        }
+    }
+}
+
+/// Written as its name, or its ID if this version doesn't know it, so it reads back the same
+impl std::fmt::Display for PersonStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unknown(id) => write!(f, "{id}"),
+            status => write!(f, "{status:?}"),
+        }
+    }
+}
+
+impl PartialOrd for PersonStatus {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PersonStatus {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        u16::from(*self).cmp(&u16::from(*other))
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
 pub enum TimelineItemType {
-    #[serde(rename = "STOPPED_TEACHING")]
     StoppedTeaching,
-    #[serde(rename = "CONTACT")]
     Contact,
-    #[serde(rename = "TEACHING")]
     Teaching,
-    #[serde(rename = "NEW_REFERRAL")]
     NewReferral,
-    #[serde(rename = "PERSON_CREATE")]
     PersonCreate,
-    #[serde(rename = "PERSON_OFFER_ITEM")]
     PersonOfferItem,
-    #[serde(rename = "SACRAMENT")]
     Sacrament,
-    #[serde(rename = "TEACHING_RESET")]
     TeachingReset,
-    #[serde(rename = "PERSON_PLN_NOTE")]
     Note,
-    #[serde(rename = "PERSON_TASK")]
     Task,
-    #[serde(rename = "EMAIL_SUBSCRIPTION")]
     EmailSubscription,
+    Unknown(String),
+}
+
+impl From<String> for TimelineItemType {
+    fn from(kind: String) -> Self {
+        match kind.as_str() {
+            "STOPPED_TEACHING" => Self::StoppedTeaching,
+            "CONTACT" => Self::Contact,
+            "TEACHING" => Self::Teaching,
+            "NEW_REFERRAL" => Self::NewReferral,
+            "PERSON_CREATE" => Self::PersonCreate,
+            "PERSON_OFFER_ITEM" => Self::PersonOfferItem,
+            "SACRAMENT" => Self::Sacrament,
+            "TEACHING_RESET" => Self::TeachingReset,
+            "PERSON_PLN_NOTE" => Self::Note,
+            "PERSON_TASK" => Self::Task,
+            "EMAIL_SUBSCRIPTION" => Self::EmailSubscription,
+            _ => Self::Unknown(kind),
+        }
+    }
+}
+
+impl From<TimelineItemType> for String {
+    fn from(kind: TimelineItemType) -> Self {
+        match kind {
+            TimelineItemType::StoppedTeaching => "STOPPED_TEACHING",
+            TimelineItemType::Contact => "CONTACT",
+            TimelineItemType::Teaching => "TEACHING",
+            TimelineItemType::NewReferral => "NEW_REFERRAL",
+            TimelineItemType::PersonCreate => "PERSON_CREATE",
+            TimelineItemType::PersonOfferItem => "PERSON_OFFER_ITEM",
+            TimelineItemType::Sacrament => "SACRAMENT",
+            TimelineItemType::TeachingReset => "TEACHING_RESET",
+            TimelineItemType::Note => "PERSON_PLN_NOTE",
+            TimelineItemType::Task => "PERSON_TASK",
+            TimelineItemType::EmailSubscription => "EMAIL_SUBSCRIPTION",
+            TimelineItemType::Unknown(kind) => return kind,
+        }
+        .to_string()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    fn line(person: &super::Person) -> String {
+        let now = chrono::Utc::now().naive_utc();
+        crate::report::ReportEntry::new(person.clone(), None, now).line()
+    }
+
     #[test]
-    fn t1() {
-        let list = std::fs::read_to_string("list.json").unwrap();
-        let list = super::Person::parse_lossy(serde_json::from_str(&list).unwrap());
-        println!("{list:?}");
+    fn corpus_people() {
+        use super::{Gender, PersonStatus, PersonStatus::*, ReferralStatus, ReferralStatus::*};
+
+        let list = crate::mock::corpus_people().to_string();
+        let (people, diagnostics) = super::Person::parse_lossy(list.as_bytes()).unwrap();
+        let statuses = people
+            .iter()
+            .map(|p| (p.guid.as_str(), p.referral_status, p.person_status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            [
+                ("c-yellow", NotAttempted, Yellow),
+                ("c-green", NotSuccessful, Green),
+                ("c-better-green", Successful, BetterGreen),
+                ("c-progressing", Successful, ProgressingGreen),
+                ("c-new-member", Successful, NewMember),
+                ("c-not-interested", NotSuccessful, NotInterested),
+                ("c-declared", NotSuccessful, NotInterestedDeclared),
+                ("c-not-progressing", Successful, NotProgressing),
+                ("c-unable", NotSuccessful, UnableToContact),
+                ("c-prank", NotSuccessful, Prank),
+                ("c-not-recent", Successful, NotRecentlyContacted),
+                ("c-too-busy", Successful, TooBusy),
+                ("c-outside", NotSuccessful, OutsideAreaStrength),
+                ("c-member", Successful, Member),
+                ("c-moved", NotSuccessful, Moved),
+                ("c-unknown-person", NotAttempted, PersonStatus::Unknown(99)),
+                ("c-unknown-referral", ReferralStatus::Unknown(15), Yellow),
+                ("c-null-zone", NotAttempted, Yellow),
+                ("c-missing-area", NotAttempted, Yellow),
+                ("c-no-district", NotAttempted, Yellow),
+                ("c-odd-extras", NotAttempted, Yellow),
+            ]
+        );
+        assert_eq!(
+            diagnostics,
+            super::ParseDiagnostics {
+                total: 24,
+                failed: 3,
+                unknown: 2,
+                broken_fields: [
+                    ("referralAssignedDate".to_string(), 1),
+                    ("referralStatusId".to_string(), 1),
+                    ("the record".to_string(), 1),
+                ]
+                .into(),
+                sample: diagnostics.sample.clone(),
+            }
+        );
+        assert_eq!(
+            diagnostics.sample.as_ref().unwrap()["firstName"],
+            "<string>"
+        );
+
+        let get = |guid: &str| people.iter().find(|p| p.guid == guid).unwrap();
+        let yellow = get("c-yellow");
+        assert_eq!(
+            line(yellow),
+            "Yara Test (Female, Adult, English, via Website) - Elder One & Elder Two"
+        );
+        assert_eq!(yellow.assigned_date.to_string(), "2024-03-01 12:00:00");
+        assert_eq!(yellow.district_name.as_deref(), Some("North District"));
+        assert_eq!(get("c-green").gender, Some(Gender::Male));
+        assert!(get("c-green").has_email());
+
+        let null_zone = get("c-null-zone");
+        assert_eq!(
+            (
+                null_zone.zone_id,
+                null_zone.district_id,
+                null_zone.area_name.as_ref()
+            ),
+            (None, None, None)
+        );
+        let missing_area = get("c-missing-area");
+        assert_eq!(missing_area.zone_name.as_deref(), Some("South"));
+        assert_eq!(missing_area.area_name, None);
+        let no_district = get("c-no-district");
+        assert_eq!(
+            (no_district.district_id, no_district.district_name.as_ref()),
+            (None, None)
+        );
+
+        let odd = get("c-odd-extras");
+        assert_eq!(
+            (odd.gender, odd.phone.as_ref(), odd.email.as_ref()),
+            (None, None, None)
+        );
+        assert!(odd.assigned_missionaries.is_empty());
+        assert_eq!(line(odd), "Odo (no phone or email)");
+    }
+
+    #[test]
+    fn corpus_timelines() {
+        use super::{ContactMethod, EventDetails, TimelineEvent, TimelineItemType::*};
+
+        let timelines = crate::mock::corpus_timelines()
+            .into_iter()
+            .map(|(guid, events)| {
+                let json = serde_json::to_vec(&events).unwrap();
+                (guid, TimelineEvent::parse_lossy(&json).unwrap())
+            })
+            .collect::<std::collections::BTreeMap<_, _>>();
+        let types = |guid: &str| {
+            timelines[guid]
+                .0
+                .iter()
+                .map(|e| e.item_type.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            types("c-yellow"),
+            [Task, Note, EmailSubscription, NewReferral]
+        );
+        assert_eq!(
+            types("c-green"),
+            [Teaching, Sacrament, Contact, NewReferral]
+        );
+        assert_eq!(
+            types("c-unknown-person"),
+            [
+                Unknown("BAPTISMAL_GOAL".to_string()),
+                TeachingReset,
+                StoppedTeaching,
+                PersonOfferItem,
+                Contact,
+                PersonCreate
+            ]
+        );
+        let diagnostics = &timelines["c-unknown-person"].1;
+        assert_eq!(
+            (diagnostics.total, diagnostics.failed, diagnostics.unknown),
+            (7, 1, 1)
+        );
+        assert_eq!(diagnostics.broken_fields["itemDate"], 1);
+        for (guid, (_, diagnostics)) in &timelines {
+            if guid != "c-unknown-person" {
+                assert_eq!((diagnostics.failed, diagnostics.unknown), (0, 0), "{guid}");
+            }
+        }
+
+        let yellow = &timelines["c-yellow"].0;
+        assert_eq!(
+            yellow[0].details,
+            EventDetails::Task {
+                description: Some("Try again".to_string()),
+                due: Some("2024-03-04T09:00:00".parse().unwrap()),
+            }
+        );
+        assert_eq!(
+            yellow[1].details,
+            EventDetails::Note {
+                text: Some("Works nights".to_string())
+            }
+        );
+
+        let green = &timelines["c-green"].0;
+        assert_eq!(
+            green[0].details,
+            EventDetails::Teaching {
+                lesson: Some("Restoration".to_string())
+            }
+        );
+        assert_eq!(green[0].recorded_by.as_deref(), Some("Elder One"));
+        assert_eq!(green[1].status, None);
+        assert_eq!(green[2].status, Some(false));
+        assert_eq!(green[2].item_date.to_string(), "2024-03-01 09:15:00");
+
+        let methods = timelines
+            .values()
+            .flat_map(|(events, _)| events)
+            .filter_map(|e| match &e.details {
+                EventDetails::Contact { method } => method.clone(),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            methods,
+            [
+                ContactMethod::Text,
+                ContactMethod::Knock,
+                ContactMethod::Call,
+                ContactMethod::SocialMedia,
+                ContactMethod::Email,
+                ContactMethod::InPerson,
+                ContactMethod::Unknown("CARRIER_PIGEON".to_string()),
+            ]
+        );
+
+        // Odd extra fields don't cost the event
+        let null_zone = &timelines["c-null-zone"].0;
+        assert_eq!(null_zone[1].recorded_by, None);
+        assert_eq!(
+            null_zone[2].details,
+            EventDetails::Task {
+                description: None,
+                due: None
+            }
+        );
+    }
+
+    #[test]
+    fn optional_fields() {
+        let mut ana = crate::mock::fixture_people().remove(0);
+        let person: super::Person = serde_json::from_value(ana.clone()).unwrap();
+        assert_eq!(person.full_name(), "Ana Lopez");
+        assert_eq!(person.gender, Some(super::Gender::Female));
+        assert!(person.has_phone() && !person.has_email());
+        assert_eq!(
+            line(&person),
+            "Ana Lopez (Female, Young adult, Spanish, via Facebook) - Elder Smith & Elder Jones"
+        );
+
+        // Odd shapes in the extra fields don't cost us the person
+        ana["gender"] = serde_json::json!("X");
+        ana["assignedMissionaries"] = serde_json::json!([{"name": "Elder Smith"}]);
+        ana["phone"] = serde_json::json!(5551234);
+        let person: super::Person = serde_json::from_value(ana).unwrap();
+        assert_eq!(person.gender, None);
+        assert!(person.assigned_missionaries.is_empty());
+        assert_eq!(
+            line(&person),
+            "Ana Lopez (Young adult, Spanish, via Facebook, no phone or email)"
+        );
+    }
+
+    #[test]
+    fn unknown_values() {
+        use super::{PersonStatus, ReferralStatus, TimelineItemType};
+
+        let list = serde_json::json!({"persons": [
+            crate::mock::person("p-new", "Ivy", 15, 99, None, None, 1),
+            crate::mock::person("p-old", "Jo", 10, 25, None, None, 1),
+        ]});
+        let (people, diagnostics) =
+            super::Person::parse_lossy(list.to_string().as_bytes()).unwrap();
+        assert_eq!(people.len(), 2);
+        assert_eq!(diagnostics.unknown, 1);
+        assert_eq!(people[0].referral_status, ReferralStatus::Unknown(15));
+        assert_eq!(people[0].person_status, PersonStatus::Unknown(99));
+        assert!(people[0].has_unknown_status());
+        assert_eq!(people[1].person_status, PersonStatus::Prank);
+        assert!(!people[1].has_unknown_status());
+
+        // Unknown statuses sort by ID among the known ones
+        assert!(PersonStatus::Unknown(5) < PersonStatus::NewMember);
+        assert!(PersonStatus::Unknown(30) > PersonStatus::OutsideAreaStrength);
+
+        // And survive a trip through the cache
+        let json = serde_json::to_value(&people[0]).unwrap();
+        assert_eq!(json["personStatusId"], 99);
+        assert_eq!(json["referralStatusId"], 15);
+
+        let events = serde_json::json!([
+            crate::mock::event("CONTACT", 1),
+            crate::mock::event("BAPTISMAL_GOAL", 2),
+        ]);
+        let (events, _) = super::TimelineEvent::parse_lossy(events.to_string().as_bytes()).unwrap();
+        assert_eq!(events[0].item_type, TimelineItemType::Contact);
+        assert_eq!(
+            events[1].item_type,
+            TimelineItemType::Unknown("BAPTISMAL_GOAL".to_string())
+        );
+        assert_eq!(
+            serde_json::to_value(&events[1]).unwrap()["timelineItemType"],
+            "BAPTISMAL_GOAL"
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut people = crate::mock::fixture_people();
+        let total = people.len();
+        people[1]["referralAssignedDate"] = serde_json::json!("yesterday");
+        let list = serde_json::json!({ "persons": people }).to_string();
+        let (list, diagnostics) = super::Person::parse_lossy(list.as_bytes()).unwrap();
+        assert_eq!(list.len(), total - 2);
+        assert_eq!(diagnostics.total, total);
+        assert_eq!(diagnostics.failed, 2);
+        assert_eq!(diagnostics.unknown, 2);
+        assert_eq!(diagnostics.broken_fields["referralAssignedDate"], 1);
+        assert_eq!(diagnostics.broken_fields["referralStatusId"], 1);
+        assert!(diagnostics.is_concerning());
+
+        // The sample shows the shape of Ben's record without anything about Ben
+        let shown = diagnostics.to_string();
+        assert!(shown.contains("lastName") && shown.contains("<string>"));
+        assert!(!shown.contains("Ben") && !shown.contains("Ng") && !shown.contains("yesterday"));
+
+        // Anything that isn't a person is skipped the same way, but a cut off response isn't
+        let list = serde_json::json!({ "persons": [people[0], 42, null] }).to_string();
+        let (list_2, diagnostics) = super::Person::parse_lossy(list.as_bytes()).unwrap();
+        assert_eq!(list_2.len(), 1);
+        assert_eq!(diagnostics.failed, 2);
+        assert!(super::Person::parse_lossy(&list.as_bytes()[..list.len() / 2]).is_err());
+
+        // A mission with nobody gets a null list
+        let (empty, diagnostics) = super::Person::parse_lossy(br#"{"persons": null}"#).unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(diagnostics.total, 0);
+        assert!(super::Person::parse_lossy(br#"{"persons": 42}"#).is_err());
+    }
+
+    #[test]
+    fn event_details() {
+        use super::{ContactMethod, EventDetails, TimelineEvent};
+        use crate::mock::{event, with};
+        use serde_json::json;
+
+        let events = json!([
+            with(
+                event("CONTACT", 1),
+                json!({"contactType": "TEXT", "recordedBy": "Elder Smith", "areaName": "Alpha"}),
+            ),
+            with(
+                event("CONTACT", 2),
+                json!({"contactType": "CARRIER_PIGEON"})
+            ),
+            with(event("TEACHING", 3), json!({"lessonName": "Restoration"})),
+            with(
+                event("PERSON_PLN_NOTE", 4),
+                json!({"note": "Prefers evenings"})
+            ),
+            with(
+                event("PERSON_TASK", 5),
+                json!({"taskDescription": "Drop by", "taskDueDate": 1700000000000i64}),
+            ),
+            // A strange due date doesn't lose the task
+            with(event("PERSON_TASK", 6), json!({"taskDueDate": "soon"})),
+            event("NEW_REFERRAL", 7),
+        ]);
+        let (events, diagnostics) =
+            TimelineEvent::parse_lossy(events.to_string().as_bytes()).unwrap();
+        assert_eq!(diagnostics.failed, 0);
+        assert_eq!(
+            events[0].details,
+            EventDetails::Contact {
+                method: Some(ContactMethod::Text)
+            }
+        );
+        assert_eq!(events[0].recorded_by.as_deref(), Some("Elder Smith"));
+        assert_eq!(events[0].area_name.as_deref(), Some("Alpha"));
+        assert_eq!(
+            events[1].details,
+            EventDetails::Contact {
+                method: Some(ContactMethod::Unknown("CARRIER_PIGEON".to_string()))
+            }
+        );
+        assert_eq!(
+            events[2].details,
+            EventDetails::Teaching {
+                lesson: Some("Restoration".to_string())
+            }
+        );
+        assert_eq!(
+            events[3].details,
+            EventDetails::Note {
+                text: Some("Prefers evenings".to_string())
+            }
+        );
+        let EventDetails::Task { due: Some(due), .. } = &events[4].details else {
+            panic!("no due date on {:?}", events[4]);
+        };
+        assert_eq!(due.and_utc().timestamp_millis(), 1700000000000);
+        assert_eq!(
+            events[5].details,
+            EventDetails::Task {
+                description: None,
+                due: None
+            }
+        );
+        assert_eq!(events[6].details, EventDetails::None);
+
+        // The details survive the timeline cache
+        let cached = serde_json::to_vec(&events).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&cached).unwrap()[0]["contactType"],
+            "TEXT"
+        );
+        let (reread, _) = TimelineEvent::parse_lossy(&cached).unwrap();
+        assert_eq!(reread, events);
     }
 }
@@ -0,0 +1,280 @@
+// Jackson Coxson
+// Missions don't all agree on who counts as uncontacted, so the rules are kept by name in
+// the working path and one is picked for each run
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use chrono::{Duration, NaiveDateTime};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{
+    env::Env,
+    filter::Filter,
+    persons::{PersonStatus, ReferralStatus},
+};
+
+pub const POLICIES_FILE: &str = "policies.json";
+/// The policy used unless ``POLICY``, ``--policy`` or Holly's settings pick another
+pub const DEFAULT_POLICY: &str = "default";
+
+/// The rules for who is uncontacted. Statuses are written by name, like ``Successful``.
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// What the policy is called in ``policies.json``
+    #[serde(skip)]
+    pub name: String,
+    /// How long someone has to have been assigned before they're reported, in hours
+    pub waiting_hours: u32,
+    /// A contact more recent than this keeps someone off the report, in hours
+    pub recent_contact_hours: u32,
+    /// Referral statuses that mean someone was reached, so they aren't reported
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub contacted: Vec<ReferralStatus>,
+    /// Only people with a person status before this one are reported or averaged
+    #[serde_as(as = "DisplayFromStr")]
+    pub status_below: PersonStatus,
+    /// Whether referrals nobody has tried yet are reported however new they are
+    pub always_include_not_attempted: bool,
+    /// How far back average contact times look, in hours
+    pub average_window_hours: u32,
+    /// Whether referrals nobody has tried yet count toward average contact times
+    pub average_not_attempted: bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_POLICY.to_string(),
+            waiting_hours: 48,
+            recent_contact_hours: 48,
+            contacted: vec![ReferralStatus::Successful],
+            status_below: PersonStatus::NewMember,
+            always_include_not_attempted: true,
+            average_window_hours: 24,
+            average_not_attempted: false,
+        }
+    }
+}
+
+impl Policy {
+    /// Reads a policy by name from the working path
+    pub fn load(env: &Env, name: &str) -> anyhow::Result<Self> {
+        Policies::load(env)?.get(name).cloned()
+    }
+
+    /// Who reports list, as a filter
+    pub fn report_rule(&self) -> String {
+        let mut rules = self
+            .contacted
+            .iter()
+            .map(|s| format!("referral!={s}"))
+            .collect::<Vec<String>>();
+        // A status this version doesn't know is most likely a new kind of progress, so
+        // those people stay on the report instead of quietly dropping off
+        rules.push(format!("(status<{} or status=unknown)", self.status_below));
+        rules.push(format!("assigned>{}h", self.waiting_hours));
+        let mut rule = format!("({})", rules.join(" and "));
+        if self.always_include_not_attempted {
+            rule = format!("{rule} or referral=NotAttempted");
+        }
+        rule
+    }
+
+    /// Who average contact times look at, as a filter
+    pub fn average_rule(&self) -> String {
+        let rule = format!(
+            "(status<{} or status=unknown) and assigned<{}h and zone!=none",
+            self.status_below, self.average_window_hours
+        );
+        if self.average_not_attempted {
+            rule
+        } else {
+            format!("referral!=NotAttempted and {rule}")
+        }
+    }
+
+    pub fn report_filter(&self) -> Filter {
+        self.report_rule()
+            .parse()
+            .expect("policies always make valid filters")
+    }
+
+    pub fn average_filter(&self) -> Filter {
+        self.average_rule()
+            .parse()
+            .expect("policies always make valid filters")
+    }
+
+    /// Whether a contact at `last` is recent enough to keep someone off the report
+    pub fn recently_contacted(&self, last: NaiveDateTime, now: NaiveDateTime) -> bool {
+        now.signed_duration_since(last) <= Duration::hours(self.recent_contact_hours as i64)
+    }
+
+    /// Takes ``--policy NAME`` or ``-p NAME`` out of the arguments after a command
+    pub fn take_arg(args: &mut Vec<String>) -> anyhow::Result<Option<String>> {
+        let Some(i) = args.iter().position(|a| {
+            a == "--policy" || a == "-p" || a.starts_with("--policy=") || a.starts_with("-p=")
+        }) else {
+            return Ok(None);
+        };
+        let arg = args.remove(i);
+        let name = match arg.split_once('=') {
+            Some((_, name)) => name.to_string(),
+            None if i < args.len() => args.remove(i),
+            None => return Err(anyhow::anyhow!("{arg} needs a policy name")),
+        };
+        check_name(&name)?;
+        Ok(Some(name))
+    }
+}
+
+/// Policy names end up in report file names, so they're kept to letters, numbers, _ and -
+pub fn check_name(name: &str) -> anyhow::Result<()> {
+    if !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{name:?} can't be a policy name, use only letters, numbers, _ and -"
+        ))
+    }
+}
+
+impl std::fmt::Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "reports {}", self.report_rule())?;
+        write!(
+            f,
+            " unless contacted in the last {}h",
+            self.recent_contact_hours
+        )?;
+        write!(f, "\n  averages {}", self.average_rule())
+    }
+}
+
+/// Every policy, by name, as saved in ``policies.json``
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Policies(pub BTreeMap<String, Policy>);
+
+impl Default for Policies {
+    fn default() -> Self {
+        Self(BTreeMap::from([(
+            DEFAULT_POLICY.to_string(),
+            Policy::default(),
+        )]))
+    }
+}
+
+impl Policies {
+    /// Reads the policies in the working path. The first time, the default policy is
+    /// written there so it can be copied and changed.
+    pub fn load(env: &Env) -> anyhow::Result<Self> {
+        let path = PathBuf::from(&env.working_path).join(POLICIES_FILE);
+        if !std::fs::exists(&path)? {
+            let policies = Self::default();
+            std::fs::write(&path, serde_json::to_string_pretty(&policies)?)?;
+            info!("Wrote the default policy to {path:?}");
+            return Ok(policies);
+        }
+        let s = std::fs::read_to_string(&path)?;
+        let mut policies: Self = serde_json::from_str(&s)
+            .map_err(|e| anyhow::anyhow!("{POLICIES_FILE} couldn't be read: {e}"))?;
+        for (name, policy) in &mut policies.0 {
+            check_name(name).map_err(|e| anyhow::anyhow!("In {POLICIES_FILE}: {e}"))?;
+            policy.name = name.clone();
+        }
+        Ok(policies)
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<&Policy> {
+        self.0.get(name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "There's no policy named {name} in {POLICIES_FILE}, there's {}",
+                self.0.keys().cloned().collect::<Vec<String>>().join(", ")
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Policies, Policy};
+    use crate::{mock, persons::ReferralStatus};
+
+    #[tokio::test]
+    async fn policies() {
+        // The default is what was hardcoded before policies
+        let default = Policy::default();
+        assert_eq!(
+            default.report_rule(),
+            "(referral!=Successful and (status<NewMember or status=unknown) and assigned>48h) or referral=NotAttempted"
+        );
+        assert_eq!(
+            default.average_rule(),
+            "referral!=NotAttempted and (status<NewMember or status=unknown) and assigned<24h and zone!=none"
+        );
+        let strict = Policy {
+            waiting_hours: 24,
+            contacted: vec![ReferralStatus::Successful, ReferralStatus::NotSuccessful],
+            always_include_not_attempted: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            strict.report_rule(),
+            "(referral!=Successful and referral!=NotSuccessful and (status<NewMember or status=unknown) and assigned>24h)"
+        );
+        strict.report_filter();
+        let patient = Policy {
+            average_not_attempted: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            patient.average_rule(),
+            "(status<NewMember or status=unknown) and assigned<24h and zone!=none"
+        );
+        patient.average_filter();
+
+        let server = mock::MockServer::with_fixtures().await;
+        let env = server.env();
+        assert_eq!(Policies::load(&env).unwrap(), Policies::default());
+        // It's saved the first time, with names people can edit
+        let path = std::path::PathBuf::from(&env.working_path).join(super::POLICIES_FILE);
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.contains(r#""status_below": "NewMember""#));
+
+        std::fs::write(
+            &path,
+            r#"{"default": {}, "weekly": {"waiting_hours": 168, "contacted": ["Successful", "20"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(Policy::load(&env, "default").unwrap(), default);
+        let weekly = Policy::load(&env, "weekly").unwrap();
+        assert_eq!(weekly.waiting_hours, 168);
+        assert_eq!(weekly.contacted[1], ReferralStatus::NotSuccessful);
+        assert_eq!(weekly.recent_contact_hours, 48);
+        let err = Policy::load(&env, "monthly").unwrap_err().to_string();
+        assert!(err.ends_with("there's default, weekly"));
+
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        let mut a = args(&["-o", "r.csv", "--policy", "weekly"]);
+        assert_eq!(Policy::take_arg(&mut a).unwrap().as_deref(), Some("weekly"));
+        assert_eq!(a, args(&["-o", "r.csv"]));
+        let mut a = args(&["--policy=weekly"]);
+        assert_eq!(Policy::take_arg(&mut a).unwrap().as_deref(), Some("weekly"));
+        assert!(a.is_empty());
+        assert!(Policy::take_arg(&mut args(&["-p"])).is_err());
+        for bad in ["../escape", "a/b", "weekly.json", ""] {
+            assert!(Policy::take_arg(&mut args(&["--policy", bad])).is_err());
+        }
+        std::fs::write(&path, r#"{"default": {}, "../weekly": {}}"#).unwrap();
+        assert!(Policies::load(&env).is_err());
+        assert_eq!(Policy::take_arg(&mut args(&["-f", "md"])).unwrap(), None);
+    }
+}
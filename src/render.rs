@@ -0,0 +1,436 @@
+// Jackson Coxson
+// Turning a report into something to read, paste into an email or open in a spreadsheet
+
+use std::{fmt::Write, path::PathBuf};
+
+use chrono::NaiveDateTime;
+
+use crate::{
+    org::OrgUnit,
+    report::{Report, ReportEntry},
+};
+
+pub trait ReportRenderer {
+    fn write(&self, report: &Report, f: &mut dyn Write) -> std::fmt::Result;
+
+    fn render(&self, report: &Report) -> String {
+        let mut res = String::new();
+        self.write(report, &mut res)
+            .expect("writing to a string can't fail");
+        res
+    }
+}
+
+/// What's printed to the terminal and sent to Holly chats
+pub struct Text;
+/// Headings and lists, for email and chats that understand Markdown
+pub struct Markdown;
+/// A page that can be opened or pasted on its own
+pub struct Html;
+/// One row per person. Suspected duplicates aren't included.
+pub struct Csv;
+/// Everything in the report, as it's saved
+pub struct Json;
+
+fn zone_name(zone_id: &usize, areas: &[(&str, Vec<&ReportEntry>)]) -> String {
+    areas
+        .iter()
+        .flat_map(|(_, people)| people)
+        .find_map(|e| e.zone_name.clone())
+        .unwrap_or(zone_id.to_string())
+}
+
+fn format_date(date: Option<NaiveDateTime>) -> String {
+    date.map(|d| d.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+impl Text {
+    /// The part of the report for one part of the mission, None if nobody there is listed
+    pub fn unit(report: &Report, unit: &OrgUnit) -> Option<String> {
+        let zones = report.zones(unit);
+        if zones.is_empty() {
+            return None;
+        }
+        let mut res = String::new();
+        if let OrgUnit::District(id) = unit {
+            let name = zones
+                .iter()
+                .flat_map(|(_, areas)| areas.iter().flat_map(|(_, people)| people))
+                .find_map(|e| e.district_name.clone())
+                .unwrap_or(format!("District {id}"));
+            res.push_str(&format!("\n{name}"));
+        }
+        for (zone_id, areas) in &zones {
+            res.push_str(&format!("\n{}", zone_name(zone_id, areas)));
+            for (area, people) in areas {
+                res.push_str(&format!("\n\n - {area}"));
+                for p in people {
+                    res.push_str(&format!("\n  - {}", p.line()));
+                }
+            }
+        }
+        Some(res)
+    }
+}
+
+impl ReportRenderer for Text {
+    fn write(&self, report: &Report, f: &mut dyn Write) -> std::fmt::Result {
+        for (zone_id, _) in report.zones(&OrgUnit::Mission) {
+            if let Some(zone) = Self::unit(report, &OrgUnit::Zone(zone_id)) {
+                write!(f, "{zone}\n\n")?;
+            }
+        }
+        write!(f, "\nUnassigned Referrals")?;
+        for p in report.unassigned() {
+            write!(f, "\n  - {}", p.line())?;
+        }
+        if !report.duplicates.is_empty() {
+            write!(f, "\n\nSuspected Duplicates")?;
+            for d in &report.duplicates {
+                write!(f, "\n  - {d}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape_markdown(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(
+            c,
+            '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '#' | '|'
+        ) {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+impl ReportRenderer for Markdown {
+    fn write(&self, report: &Report, f: &mut dyn Write) -> std::fmt::Result {
+        writeln!(f, "# Uncontacted Referrals")?;
+        for (zone_id, areas) in &report.zones(&OrgUnit::Mission) {
+            write!(f, "\n## {}\n", escape_markdown(&zone_name(zone_id, areas)))?;
+            for (area, people) in areas {
+                write!(f, "\n### {}\n\n", escape_markdown(area))?;
+                for p in people {
+                    writeln!(f, "- {}", escape_markdown(&p.line()))?;
+                }
+            }
+        }
+        write!(f, "\n## Unassigned Referrals\n\n")?;
+        for p in report.unassigned() {
+            writeln!(f, "- {}", escape_markdown(&p.line()))?;
+        }
+        if !report.duplicates.is_empty() {
+            write!(f, "\n## Suspected Duplicates\n\n")?;
+            for d in &report.duplicates {
+                writeln!(f, "- {}", escape_markdown(d))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn html_list(f: &mut dyn Write, lines: impl Iterator<Item = String>) -> std::fmt::Result {
+    writeln!(f, "<ul>")?;
+    for line in lines {
+        writeln!(f, "<li>{}</li>", escape_html(&line))?;
+    }
+    writeln!(f, "</ul>")
+}
+
+impl ReportRenderer for Html {
+    fn write(&self, report: &Report, f: &mut dyn Write) -> std::fmt::Result {
+        writeln!(f, "<!DOCTYPE html>")?;
+        writeln!(f, "<html>")?;
+        writeln!(f, "<head>")?;
+        writeln!(f, "<meta charset=\"utf-8\">")?;
+        writeln!(f, "<title>Uncontacted Referrals</title>")?;
+        writeln!(f, "</head>")?;
+        writeln!(f, "<body>")?;
+        writeln!(f, "<h1>Uncontacted Referrals</h1>")?;
+        for (zone_id, areas) in &report.zones(&OrgUnit::Mission) {
+            writeln!(f, "<h2>{}</h2>", escape_html(&zone_name(zone_id, areas)))?;
+            for (area, people) in areas {
+                writeln!(f, "<h3>{}</h3>", escape_html(area))?;
+                html_list(f, people.iter().map(|p| p.line()))?;
+            }
+        }
+        writeln!(f, "<h2>Unassigned Referrals</h2>")?;
+        html_list(f, report.unassigned().map(|p| p.line()))?;
+        if !report.duplicates.is_empty() {
+            writeln!(f, "<h2>Suspected Duplicates</h2>")?;
+            html_list(f, report.duplicates.iter().cloned())?;
+        }
+        writeln!(f, "</body>")?;
+        writeln!(f, "</html>")
+    }
+}
+
+/// Quotes a field that would break the row, and starts anything but a plain number that a
+/// spreadsheet would run as a formula with a ``'``
+fn csv_field(s: &str) -> String {
+    let number = s
+        .strip_prefix(['-', '+'])
+        .is_some_and(|n| n.starts_with(|c: char| c.is_ascii_digit()) && n.parse::<f64>().is_ok());
+    let s = if !number && s.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{s}")
+    } else {
+        s.to_string()
+    };
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+impl ReportRenderer for Csv {
+    fn write(&self, report: &Report, f: &mut dyn Write) -> std::fmt::Result {
+        writeln!(
+            f,
+            "zone,district,area,name,details,missionaries,assigned,hours_waiting,last_contact,person_status,referral_status,guid"
+        )?;
+        // Zones in order, then the unassigned, like the other formats
+        let assigned = report
+            .zones(&OrgUnit::Mission)
+            .into_iter()
+            .flat_map(|(_, areas)| areas.into_iter().flat_map(|(_, people)| people))
+            .collect::<Vec<&ReportEntry>>();
+        for e in assigned.into_iter().chain(report.unassigned()) {
+            let row = [
+                e.zone_name
+                    .clone()
+                    .or(e.zone_id.map(|z| z.to_string()))
+                    .unwrap_or_default(),
+                e.district_name
+                    .clone()
+                    .or(e.district_id.map(|d| d.to_string()))
+                    .unwrap_or_default(),
+                e.area_name.clone().unwrap_or_default(),
+                e.name.clone(),
+                e.details.join("; "),
+                e.missionaries.join(" & "),
+                format_date(e.assigned_date),
+                e.hours_waiting.map(|h| h.to_string()).unwrap_or_default(),
+                format_date(e.last_contact),
+                e.person_status.map(|s| s.to_string()).unwrap_or_default(),
+                e.referral_status.map(|s| s.to_string()).unwrap_or_default(),
+                e.guid.clone().unwrap_or_default(),
+            ];
+            writeln!(
+                f,
+                "{}",
+                row.iter()
+                    .map(|s| csv_field(s))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl ReportRenderer for Json {
+    fn write(&self, report: &Report, f: &mut dyn Write) -> std::fmt::Result {
+        let json = serde_json::to_string_pretty(report).map_err(|_| std::fmt::Error)?;
+        writeln!(f, "{json}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Text,
+    Markdown,
+    Html,
+    Csv,
+    Json,
+}
+
+impl Format {
+    pub fn renderer(&self) -> &'static dyn ReportRenderer {
+        match self {
+            Self::Text => &Text,
+            Self::Markdown => &Markdown,
+            Self::Html => &Html,
+            Self::Csv => &Csv,
+            Self::Json => &Json,
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    /// Takes the format's name or its usual file extension
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" | "txt" => Ok(Self::Text),
+            "markdown" | "md" => Ok(Self::Markdown),
+            "html" | "htm" => Ok(Self::Html),
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown report format {s}, use text, markdown, html, csv or json"
+            )),
+        }
+    }
+}
+
+/// How and where to write a report, from ``--format`` and ``--output``
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Output {
+    pub format: Format,
+    /// Printed when there's no file
+    pub path: Option<PathBuf>,
+}
+
+impl Output {
+    /// Reads ``--format``/``-f`` and ``--output``/``-o``, or the file's extension
+    pub fn from_args(args: &[String]) -> anyhow::Result<Self> {
+        let mut format = None;
+        let mut path = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let value = || {
+                value
+                    .or_else(|| args.next().cloned())
+                    .ok_or_else(|| anyhow::anyhow!("{flag} needs a value"))
+            };
+            match flag {
+                "--format" | "-f" => format = Some(value()?.parse().map_err(anyhow::Error::msg)?),
+                "--output" | "-o" => path = Some(PathBuf::from(value()?)),
+                _ => return Err(anyhow::anyhow!("Unknown option '{arg}'")),
+            }
+        }
+        let format = format
+            .or_else(|| {
+                path.as_ref()
+                    .and_then(|p: &PathBuf| p.extension())
+                    .and_then(|e| e.to_str())
+                    .and_then(|e| e.parse().ok())
+            })
+            .unwrap_or_default();
+        Ok(Self { format, path })
+    }
+
+    pub fn write(&self, report: &Report) -> anyhow::Result<()> {
+        let rendered = self.format.renderer().render(report);
+        match &self.path {
+            Some(path) => {
+                std::fs::write(path, rendered)?;
+                println!("Wrote the report to {path:?}");
+            }
+            None => println!("{rendered}"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Output, ReportRenderer};
+    use crate::mock::{people, person, with, NORTH, SOUTH};
+    use serde_json::json;
+
+    fn report() -> crate::report::Report {
+        let people = people(&[
+            with(
+                person("p-1", "Ana", 10, 1, Some(NORTH), Some("Alpha"), 72),
+                json!({"lastName": "Lopez, Jr.", "preferredLanguage": "Spanish"}),
+            ),
+            person("p-2", "<Ben>", 10, 1, Some(SOUTH), Some("Bravo"), 50),
+            person("p-3", "Fay", 10, 1, None, None, 50),
+            person("p-4", "Gil", 10, 99, None, None, 60),
+        ]);
+        let mut report = crate::report::Report::new();
+        for p in people {
+            report.add_person(p, None);
+        }
+        report.duplicates = vec!["Ana & Annie - same phone".to_string()];
+        report
+    }
+
+    #[test]
+    fn formats() {
+        let report = report();
+
+        let text = super::Text.render(&report);
+        assert!(text
+            .starts_with("\nNorth\n\n - Alpha\n  - Ana Lopez, Jr. (Spanish, no phone or email)"));
+        // Everyone unassigned is listed, not just the last one
+        assert!(text.contains("\nUnassigned Referrals\n  - Fay (no phone or email)\n  - Gil (no phone or email)\n\nSuspected"));
+        assert_eq!(text, report.pretty_print());
+
+        let markdown = super::Markdown.render(&report);
+        assert!(markdown.starts_with("# Uncontacted Referrals\n\n## North\n\n### Alpha\n\n- Ana"));
+        assert!(markdown.contains("- \\<Ben\\> (no phone or email)"));
+
+        let html = super::Html.render(&report);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("<h3>Bravo</h3>\n<ul>\n<li>&lt;Ben&gt; (no phone or email)</li>"));
+        assert!(html.trim_end().ends_with("</html>"));
+
+        let csv = super::Csv.render(&report);
+        let rows = csv.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 5);
+        assert!(rows[0].starts_with("zone,district,area,name,"));
+        assert!(rows[1].starts_with(
+            "North,North District,Alpha,\"Ana Lopez, Jr.\",Spanish; no phone or email,"
+        ));
+        assert!(rows[1].ends_with(",72,,Yellow,NotAttempted,p-1"));
+        assert!(rows[4].starts_with(",,,Gil,"));
+        // Statuses this version doesn't know are written by ID, like everywhere else
+        assert!(rows[4].ends_with(",99,NotAttempted,p-4"));
+        assert_eq!(
+            super::csv_field("=HYPERLINK(\"x\")"),
+            "\"'=HYPERLINK(\"\"x\"\")\""
+        );
+        assert_eq!(super::csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(super::csv_field("+1 555 0100"), "'+1 555 0100");
+        assert_eq!(super::csv_field("Ana-Maria"), "Ana-Maria");
+        assert_eq!(super::csv_field("-3"), "-3");
+        assert_eq!(super::csv_field("-2.5"), "-2.5");
+        assert_eq!(super::csv_field("-inf"), "'-inf");
+        assert_eq!(super::csv_field("-1+1"), "'-1+1");
+
+        let json = super::Json.render(&report);
+        let reread = serde_json::from_str::<crate::report::Report>(&json).unwrap();
+        assert_eq!(reread.entries, report.entries);
+    }
+
+    #[test]
+    fn output_args() {
+        let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(Output::from_args(&[]).unwrap(), Output::default());
+
+        let out = Output::from_args(&args(&["-o", "today.md"])).unwrap();
+        assert_eq!(out.format, Format::Markdown);
+        let out = Output::from_args(&args(&["--output=today.txt", "--format", "csv"])).unwrap();
+        assert_eq!(out.format, Format::Csv);
+        assert_eq!(out.path.unwrap().to_str(), Some("today.txt"));
+        let out = Output::from_args(&args(&["-o", "report"])).unwrap();
+        assert_eq!(out.format, Format::Text);
+
+        assert!(Output::from_args(&args(&["--format", "pdf"])).is_err());
+        assert!(Output::from_args(&args(&["--format"])).is_err());
+        assert!(Output::from_args(&args(&["--verbose"])).is_err());
+    }
+}
@@ -1,92 +1,279 @@
 // Jackson Coxson
 
-use std::{collections::HashMap, path::PathBuf, str::FromStr};
+use std::{cmp::Ordering, collections::HashMap, path::PathBuf, str::FromStr};
 
+use chrono::NaiveDateTime;
 use log::info;
 use serde::{Deserialize, Serialize};
 
-use crate::persons::Person;
+use crate::{
+    org::OrgUnit,
+    persons::{Person, PersonStatus, ReferralStatus},
+    policy::DEFAULT_POLICY,
+    render::{ReportRenderer, Text},
+};
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+const NO_AREA: &str = "NO AREA";
+
+/// Someone on the report, with enough to tell them apart and follow up on them
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReportEntry {
+    /// Missing on entries from reports saved before entries were kept
+    pub guid: Option<String>,
+    pub name: String,
+    /// What goes in the parentheses, like gender and language
+    #[serde(default)]
+    pub details: Vec<String>,
+    #[serde(default)]
+    pub missionaries: Vec<String>,
+    pub assigned_date: Option<NaiveDateTime>,
+    /// How long they'd waited when the report was made
+    pub hours_waiting: Option<i64>,
+    pub last_contact: Option<NaiveDateTime>,
+    pub person_status: Option<PersonStatus>,
+    pub referral_status: Option<ReferralStatus>,
+    pub zone_id: Option<usize>,
+    pub zone_name: Option<String>,
+    pub district_id: Option<usize>,
+    pub district_name: Option<String>,
+    pub area_name: Option<String>,
+}
+
+impl ReportEntry {
+    pub fn new(person: Person, last_contact: Option<NaiveDateTime>, now: NaiveDateTime) -> Self {
+        Self {
+            details: person.report_details(),
+            name: person.full_name(),
+            guid: Some(person.guid),
+            missionaries: person.assigned_missionaries,
+            assigned_date: Some(person.assigned_date),
+            hours_waiting: Some(now.signed_duration_since(person.assigned_date).num_hours()),
+            last_contact,
+            person_status: Some(person.person_status),
+            referral_status: Some(person.referral_status),
+            zone_id: person.zone_id,
+            zone_name: person.zone_name,
+            district_id: person.district_id,
+            district_name: person.district_name,
+            area_name: person.area_name,
+        }
+    }
+
+    /// The entry as a line in a report, see [Person::report_details]
+    pub fn line(&self) -> String {
+        let mut line = self.name.clone();
+        if !self.details.is_empty() {
+            line = format!("{line} ({})", self.details.join(", "));
+        }
+        if !self.missionaries.is_empty() {
+            line = format!("{line} - {}", self.missionaries.join(" & "));
+        }
+        line
+    }
+
+    /// What the same referral has in every report, or its line and place in old ones
+    pub fn key(&self) -> String {
+        match &self.guid {
+            Some(guid) => guid.clone(),
+            None => format!("{}|{:?}|{}", self.name, self.zone_id, self.area()),
+        }
+    }
+
+    pub fn area(&self) -> &str {
+        self.area_name.as_deref().unwrap_or(NO_AREA)
+    }
+}
+
+/// Something report entries can be sorted by
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Zone,
+    Area,
+    /// Longest waiting first
+    Waiting,
+    /// Most recently assigned first
+    Recent,
+}
+
+impl std::str::FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "zone" => Ok(Self::Zone),
+            "area" => Ok(Self::Area),
+            "waiting" => Ok(Self::Waiting),
+            "recent" => Ok(Self::Recent),
+            _ => Err(format!(
+                "unknown sort key {s}, use zone, area, waiting or recent"
+            )),
+        }
+    }
+}
+
+/// The keys reports are sorted by, most important first. Ties go by zone, area, name and GUID.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportOrder(pub Vec<SortKey>);
+
+impl Default for ReportOrder {
+    fn default() -> Self {
+        Self(vec![SortKey::Zone, SortKey::Area, SortKey::Waiting])
+    }
+}
+
+impl ReportOrder {
+    /// Reads ``REPORT_ORDER``, a comma separated list of sort keys like ``zone,area,waiting``
+    pub fn from_vars() -> anyhow::Result<Self> {
+        match std::env::var("REPORT_ORDER") {
+            Ok(order) => order
+                .parse()
+                .map_err(|e| anyhow::anyhow!("REPORT_ORDER isn't valid: {e}")),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn compare(&self, a: &ReportEntry, b: &ReportEntry) -> Ordering {
+        let by_zone = |a: &ReportEntry, b: &ReportEntry| {
+            a.zone_name
+                .cmp(&b.zone_name)
+                .then(a.zone_id.cmp(&b.zone_id))
+        };
+        let by_area = |a: &ReportEntry, b: &ReportEntry| a.area_name.cmp(&b.area_name);
+        self.0
+            .iter()
+            .fold(Ordering::Equal, |ord, key| {
+                ord.then_with(|| match key {
+                    SortKey::Zone => by_zone(a, b),
+                    SortKey::Area => by_area(a, b),
+                    SortKey::Waiting => b.hours_waiting.cmp(&a.hours_waiting),
+                    SortKey::Recent => b.assigned_date.cmp(&a.assigned_date),
+                })
+            })
+            .then_with(|| by_zone(a, b))
+            .then_with(|| by_area(a, b))
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.guid.cmp(&b.guid))
+    }
+}
+
+impl std::str::FromStr for ReportOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s
+            .split(',')
+            .filter(|k| !k.trim().is_empty())
+            .map(|k| k.parse())
+            .collect::<Result<Vec<SortKey>, String>>()?;
+        if keys.is_empty() {
+            return Err("no sort keys given".to_string());
+        }
+        Ok(Self(keys))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "SavedReport")]
 pub struct Report {
-    people: HashMap<usize, HashMap<String, Vec<String>>>,
-    zones: HashMap<usize, String>,
-    pub unassigned: Vec<String>,
+    pub entries: Vec<ReportEntry>,
+    /// Suspected duplicate referrals, for the referral secretary
+    pub duplicates: Vec<String>,
+    /// The name of the policy that picked who is on the report
+    pub policy: String,
 }
 
+impl Default for Report {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            duplicates: Vec::new(),
+            policy: DEFAULT_POLICY.to_string(),
+        }
+    }
+}
+
+/// Entries grouped by zone ID and then area name, in the order the report is sorted
+pub type Zones<'a> = Vec<(usize, Vec<(&'a str, Vec<&'a ReportEntry>)>)>;
+
 impl Report {
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn add_person(&mut self, person: Person) {
-        if let Some(zone_id) = person.zone_id {
-            let zone = match self.people.get_mut(&zone_id) {
-                Some(z) => z,
+    pub fn add_person(&mut self, person: Person, last_contact: Option<NaiveDateTime>) {
+        let now = chrono::Utc::now().naive_utc();
+        self.entries
+            .push(ReportEntry::new(person, last_contact, now));
+    }
+
+    /// Puts the entries in order, so everything that reads the report lists them the same way
+    pub fn sort(&mut self, order: &ReportOrder) {
+        self.entries.sort_by(|a, b| order.compare(a, b));
+    }
+
+    /// People who haven't been given to a zone yet
+    pub fn unassigned(&self) -> impl Iterator<Item = &ReportEntry> {
+        self.entries.iter().filter(|e| e.zone_id.is_none())
+    }
+
+    /// Entries under a part of the mission, grouped by zone ID and then area
+    pub fn zones(&self, unit: &OrgUnit) -> Zones<'_> {
+        let mut zones = Zones::new();
+        for entry in &self.entries {
+            let Some(zone_id) = entry.zone_id else {
+                continue;
+            };
+            let included = match unit {
+                OrgUnit::Mission => true,
+                OrgUnit::Zone(id) => zone_id == *id,
+                OrgUnit::District(id) => entry.district_id == Some(*id),
+                OrgUnit::Area(zone, name) => {
+                    zone_id == *zone && entry.area_name.as_ref() == Some(name)
+                }
+            };
+            if !included {
+                continue;
+            }
+            let areas = match zones.iter().position(|(id, _)| *id == zone_id) {
+                Some(i) => &mut zones[i].1,
                 None => {
-                    self.zones
-                        .insert(zone_id, person.zone_name.unwrap_or(zone_id.to_string()));
-                    self.people.insert(zone_id, HashMap::new());
-                    self.people.get_mut(&zone_id).unwrap()
+                    zones.push((zone_id, Vec::new()));
+                    &mut zones.last_mut().unwrap().1
                 }
             };
-            let area_name = person.area_name.unwrap_or("NO AREA".to_string());
-            if let Some(area) = zone.get_mut(&area_name) {
-                area.push(person.first_name);
-            } else {
-                zone.insert(area_name, vec![person.first_name]);
+            match areas.iter().position(|(area, _)| *area == entry.area()) {
+                Some(i) => areas[i].1.push(entry),
+                None => areas.push((entry.area(), vec![entry])),
             }
-        } else {
-            self.unassigned.push(person.first_name)
         }
+        zones
     }
 
     pub fn pretty_print(&self) -> String {
-        let mut res = "".to_string();
-        for (zone_id, areas) in &self.people {
-            res = format!("{res}{}", self.pretty_print_zone(zone_id, areas));
-            res = format!("{res}\n\n");
-        }
-        res = format!("{res}\nUnassigned Referrals");
-        for p in &self.unassigned {
-            res = format!("  - {p}")
-        }
-        res
+        Text.render(self)
     }
 
-    fn pretty_print_zone(&self, zone_id: &usize, areas: &HashMap<String, Vec<String>>) -> String {
-        let mut res = "".to_string();
-        let zone_name = &zone_id.to_string();
-        let zone_name = self.zones.get(zone_id).unwrap_or(zone_name);
-
-        res = format!("{res}\n{zone_name}");
-        for (area, people) in areas {
-            res = format!("{res}\n\n - {area}");
-            for p in people {
-                res = format!("{res}\n  - {p}");
-            }
-        }
-        res
+    pub fn get_pretty_zone(&self, zone_id: &usize) -> Option<String> {
+        self.get_pretty_unit(&OrgUnit::Zone(*zone_id))
     }
 
-    pub fn get_pretty_zone(&self, zone_id: &usize) -> Option<String> {
-        let areas = self.people.get(zone_id)?;
-        Some(self.pretty_print_zone(zone_id, areas))
+    /// The part of the report for any part of the mission, None if nobody there is listed
+    pub fn get_pretty_unit(&self, unit: &OrgUnit) -> Option<String> {
+        Text::unit(self, unit)
     }
 
     pub fn save_report(&self, env: &crate::env::Env) -> anyhow::Result<()> {
         info!("Saving report");
-        let today = chrono::Local::now();
-        let today_str = today.format("%Y-%m-%d").to_string();
+        let today = chrono::Local::now().date_naive();
 
-        let reports_path = PathBuf::from_str(&env.working_path)?.join("reports");
+        let reports_path = PathBuf::from_str(&env.working_path)?.join(crate::history::REPORTS_DIR);
         std::fs::create_dir_all(&reports_path)?;
 
         let file = std::fs::OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(reports_path.join(format!("{today_str}.json")))?;
+            .open(reports_path.join(crate::history::file_name(today, &self.policy)))?;
         serde_json::to_writer(file, self)?;
 
         info!("Saved report to {reports_path:?}");
@@ -94,18 +281,184 @@ impl Report {
         Ok(())
     }
 
-    pub fn read_report(env: &crate::env::Env) -> anyhow::Result<Option<Self>> {
-        let today = chrono::Local::now();
-        let today_str = today.format("%Y-%m-%d").to_string();
+    /// Today's report under a policy, if one was made
+    pub fn read_report(env: &crate::env::Env, policy: &str) -> anyhow::Result<Option<Self>> {
+        Self::read_report_on(env, chrono::Local::now().date_naive(), policy)
+    }
 
-        let reports_path = PathBuf::from_str(&env.working_path)?.join("reports");
+    /// The report saved on a day under a policy, if there is one
+    pub fn read_report_on(
+        env: &crate::env::Env,
+        date: chrono::NaiveDate,
+        policy: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let reports_path = PathBuf::from_str(&env.working_path)?.join(crate::history::REPORTS_DIR);
         std::fs::create_dir_all(&reports_path)?;
 
-        if std::fs::exists(reports_path.join(format!("{today_str}.json")))? {
-            let s = std::fs::read_to_string(reports_path.join(format!("{today_str}.json")))?;
-            Ok(Some(serde_json::from_str(&s)?))
+        let path = reports_path.join(crate::history::file_name(date, policy));
+        if std::fs::exists(&path)? {
+            let s = std::fs::read_to_string(&path)?;
+            let mut report: Self = serde_json::from_str(&s)?;
+            if report.policy != policy {
+                return Ok(None);
+            }
+            // The order may have been changed since it was saved
+            report.sort(&env.report_order);
+            Ok(Some(report))
         } else {
             Ok(None)
         }
     }
 }
+
+/// A report as saved now, or by a version that only kept the lines that were shown
+#[derive(Deserialize)]
+struct SavedReport {
+    #[serde(default)]
+    entries: Vec<ReportEntry>,
+    #[serde(default)]
+    duplicates: Vec<String>,
+    /// Reports from before policies were all made by the default one
+    #[serde(default = "default_policy")]
+    policy: String,
+    #[serde(default)]
+    people: HashMap<usize, HashMap<String, Vec<String>>>,
+    #[serde(default)]
+    zones: HashMap<usize, String>,
+    #[serde(default)]
+    unassigned: Vec<String>,
+    #[serde(default)]
+    area_districts: HashMap<String, usize>,
+    #[serde(default)]
+    district_names: HashMap<usize, String>,
+}
+
+fn default_policy() -> String {
+    DEFAULT_POLICY.to_string()
+}
+
+impl From<SavedReport> for Report {
+    fn from(saved: SavedReport) -> Self {
+        let line_entry = |line: String| ReportEntry {
+            guid: None,
+            name: line,
+            details: Vec::new(),
+            missionaries: Vec::new(),
+            assigned_date: None,
+            hours_waiting: None,
+            last_contact: None,
+            person_status: None,
+            referral_status: None,
+            zone_id: None,
+            zone_name: None,
+            district_id: None,
+            district_name: None,
+            area_name: None,
+        };
+        let mut entries = saved.entries;
+        for (zone_id, areas) in saved.people {
+            for (area, lines) in areas {
+                let district_id = saved.area_districts.get(&area).copied();
+                for line in lines {
+                    entries.push(ReportEntry {
+                        zone_id: Some(zone_id),
+                        zone_name: saved.zones.get(&zone_id).cloned(),
+                        district_id,
+                        district_name: district_id
+                            .and_then(|d| saved.district_names.get(&d).cloned()),
+                        area_name: (area != NO_AREA).then(|| area.clone()),
+                        ..line_entry(line)
+                    });
+                }
+            }
+        }
+        entries.extend(saved.unassigned.into_iter().map(line_entry));
+        Self {
+            entries,
+            duplicates: saved.duplicates,
+            policy: saved.policy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{people, person, NORTH, SOUTH};
+    use crate::org::OrgUnit;
+
+    #[test]
+    fn ordering() {
+        let people = people(&[
+            person("p-1", "Ana", 10, 1, Some(SOUTH), Some("Bravo"), 50),
+            person("p-2", "Ben", 10, 1, Some(NORTH), Some("Beta"), 60),
+            person("p-3", "Cy", 10, 1, Some(NORTH), Some("Alpha"), 55),
+            person("p-4", "Di", 10, 1, Some(NORTH), Some("Alpha"), 90),
+            person("p-5", "Ed", 10, 1, None, None, 70),
+        ]);
+        let mut report = super::Report::new();
+        for p in people {
+            report.add_person(p, None);
+        }
+        let names = |report: &super::Report| {
+            report
+                .zones(&OrgUnit::Mission)
+                .into_iter()
+                .flat_map(|(_, areas)| areas.into_iter().flat_map(|(_, people)| people))
+                .chain(report.unassigned())
+                .map(|e| e.name.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        report.sort(&super::ReportOrder::default());
+        assert_eq!(names(&report), "Di Cy Ben Ana Ed");
+        report.sort(&"waiting".parse().unwrap());
+        // Zones and areas follow whoever has waited longest in them
+        assert_eq!(names(&report), "Di Cy Ben Ana Ed");
+        report.sort(&"recent".parse().unwrap());
+        assert_eq!(names(&report), "Ana Cy Di Ben Ed");
+        report.sort(&"area, recent".parse().unwrap());
+        assert_eq!(names(&report), "Cy Di Ben Ana Ed");
+
+        // Sorting doesn't depend on the order people came in
+        let mut reversed = report.clone();
+        reversed.entries.reverse();
+        reversed.sort(&super::ReportOrder::default());
+        report.sort(&super::ReportOrder::default());
+        assert_eq!(reversed.entries, report.entries);
+
+        assert!("zone,age".parse::<super::ReportOrder>().is_err());
+        assert!(" , ".parse::<super::ReportOrder>().is_err());
+    }
+
+    #[test]
+    fn old_reports_load() {
+        let saved = serde_json::json!({
+            "people": {"1": {"Alpha": ["Ana Lopez (Spanish)", "Ben"], "NO AREA": ["Gil"]}},
+            "zones": {"1": "North"},
+            "unassigned": ["Fay"],
+            "area_districts": {"Alpha": 10},
+            "district_names": {"10": "North District"}
+        });
+        let report = serde_json::from_value::<super::Report>(saved).unwrap();
+        assert_eq!(report.entries.len(), 4);
+        assert_eq!(
+            report.unassigned().map(|e| e.line()).collect::<Vec<_>>(),
+            ["Fay"]
+        );
+        let north = report.get_pretty_zone(&1).unwrap();
+        assert!(north.starts_with("\nNorth"));
+        assert!(north.contains(" - Alpha\n  - Ana Lopez (Spanish)\n  - Ben"));
+        assert!(north.contains(" - NO AREA\n  - Gil"));
+        let district = report.get_pretty_unit(&OrgUnit::District(10)).unwrap();
+        assert!(district.starts_with("\nNorth District"));
+        assert!(!district.contains("Gil"));
+
+        // Saved again, it's read back as entries
+        let resaved = serde_json::to_value(&report).unwrap();
+        assert!(resaved.get("entries").is_some());
+        let reread = serde_json::from_value::<super::Report>(resaved).unwrap();
+        assert_eq!(reread.entries.len(), 4);
+        assert_eq!(reread.get_pretty_zone(&1).unwrap(), north);
+    }
+}
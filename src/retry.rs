@@ -0,0 +1,216 @@
+// Jackson Coxson
+// How hard to try when the church servers don't cooperate
+
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use rand::Rng;
+
+use crate::error::ChurchError;
+
+pub const DEFAULT_MAX_TRIES: u8 = 3;
+pub const DEFAULT_BASE_DELAY_MS: u64 = 500;
+pub const DEFAULT_MAX_DELAY_SECS: u64 = 30;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How many times to try a request before giving up, including the first
+    pub max_tries: u8,
+    /// The wait before the first retry, doubled for every retry after
+    pub base_delay: Duration,
+    /// The longest to ever wait between tries, even if the server asks for longer
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_tries: DEFAULT_MAX_TRIES,
+            base_delay: Duration::from_millis(DEFAULT_BASE_DELAY_MS),
+            max_delay: Duration::from_secs(DEFAULT_MAX_DELAY_SECS),
+        }
+    }
+}
+
+/// What to do about a failed request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryAction {
+    /// The session is bad, log in and try again
+    Relogin,
+    /// The server is struggling or we lost the connection, wait and try again
+    Wait,
+    /// Trying again won't help
+    GiveUp,
+}
+
+impl From<&ChurchError> for RetryAction {
+    fn from(e: &ChurchError) -> Self {
+        if !e.is_retryable() {
+            Self::GiveUp
+        } else if e.needs_login() {
+            Self::Relogin
+        } else {
+            Self::Wait
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads ``RETRY_MAX_TRIES``, ``RETRY_BASE_DELAY_MS`` and ``RETRY_MAX_DELAY_SECS``,
+    /// falling back to the defaults
+    pub fn from_vars() -> Self {
+        fn var<T: std::str::FromStr>(key: &str) -> Option<T> {
+            std::env::var(key).ok()?.parse().ok()
+        }
+        let default = Self::default();
+        Self {
+            max_tries: var("RETRY_MAX_TRIES")
+                .filter(|t| *t > 0)
+                .unwrap_or(default.max_tries),
+            base_delay: var("RETRY_BASE_DELAY_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.base_delay),
+            max_delay: var("RETRY_MAX_DELAY_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.max_delay),
+        }
+    }
+
+    /// How long to wait before the given retry, counting from 1.
+    /// A Retry-After from the server wins, otherwise it's exponential backoff with jitter
+    /// so parallel requests that failed together don't all come back together.
+    pub fn delay(&self, retry: u8, e: &ChurchError) -> Duration {
+        if let Some(after) = e.retry_after() {
+            return after.min(self.max_delay);
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1) as u32))
+            .min(self.max_delay);
+        let half = backoff / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+/// Counts what the retry policy had to do, so a run can say how rough it was
+#[derive(Debug, Default)]
+pub struct RetryCounters {
+    retries: AtomicUsize,
+    relogins: AtomicUsize,
+    rate_limited: AtomicUsize,
+    gave_up: AtomicUsize,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetryStats {
+    pub retries: usize,
+    pub relogins: usize,
+    pub rate_limited: usize,
+    pub gave_up: usize,
+}
+
+impl RetryCounters {
+    pub fn retried(&self, action: RetryAction, e: &ChurchError) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        if action == RetryAction::Relogin {
+            self.relogins.fetch_add(1, Ordering::Relaxed);
+        }
+        if matches!(e.root(), ChurchError::RateLimited { .. }) {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn gave_up(&self) {
+        self.gave_up.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gets the counts and starts them over
+    pub fn take(&self) -> RetryStats {
+        RetryStats {
+            retries: self.retries.swap(0, Ordering::Relaxed),
+            relogins: self.relogins.swap(0, Ordering::Relaxed),
+            rate_limited: self.rate_limited.swap(0, Ordering::Relaxed),
+            gave_up: self.gave_up.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+impl RetryStats {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Display for RetryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Needed {} retries ({} to log in again, {} rate limited), gave up on {} requests",
+            self.retries, self.relogins, self.rate_limited, self.gave_up
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::StatusCode;
+
+    use super::{RetryAction, RetryPolicy};
+    use crate::error::ChurchError;
+
+    fn status(code: u16) -> ChurchError {
+        ChurchError::Status {
+            status: StatusCode::from_u16(code).unwrap(),
+            url: "http://localhost".to_string(),
+        }
+    }
+
+    #[test]
+    fn classify() {
+        assert_eq!(RetryAction::from(&status(401)), RetryAction::Relogin);
+        assert_eq!(RetryAction::from(&status(403)), RetryAction::Relogin);
+        assert_eq!(RetryAction::from(&status(500)), RetryAction::Wait);
+        assert_eq!(RetryAction::from(&status(503)), RetryAction::Wait);
+        assert_eq!(RetryAction::from(&status(404)), RetryAction::GiveUp);
+        assert_eq!(RetryAction::from(&status(400)), RetryAction::GiveUp);
+        let limited = ChurchError::RateLimited {
+            url: "http://localhost".to_string(),
+            retry_after: None,
+        };
+        assert_eq!(RetryAction::from(&limited), RetryAction::Wait);
+        // Logging in again won't fix a response we can't read
+        let parse = ChurchError::Parse("the people list".to_string());
+        assert_eq!(RetryAction::from(&parse), RetryAction::Wait);
+        let auth = ChurchError::Auth("Password is incorrect".to_string());
+        assert_eq!(RetryAction::from(&auth), RetryAction::GiveUp);
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            max_tries: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+        let e = status(500);
+        for _ in 0..20 {
+            let first = policy.delay(1, &e);
+            assert!(first >= Duration::from_millis(500) && first <= Duration::from_secs(1));
+            let third = policy.delay(3, &e);
+            assert!(third >= Duration::from_secs(2) && third <= Duration::from_secs(4));
+            let capped = policy.delay(10, &e);
+            assert!(capped >= Duration::from_millis(2500) && capped <= Duration::from_secs(5));
+        }
+
+        let limited = ChurchError::RateLimited {
+            url: "http://localhost".to_string(),
+            retry_after: Some(Duration::from_secs(60)),
+        };
+        assert_eq!(policy.delay(1, &limited), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,280 @@
+// Jackson Coxson
+// The people lists saved in the cache, and what changed between them
+
+use std::{collections::HashMap, path::PathBuf};
+
+use chrono::{DateTime, Local};
+
+use crate::{
+    env::Env,
+    error::ChurchError,
+    persons::{PeopleList, Person, PersonStatus},
+};
+
+pub const SNAPSHOTS_DIR: &str = "people_lists";
+
+/// A people list saved by the cache, named by the unix time it was fetched
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub taken: u64,
+    path: PathBuf,
+}
+
+impl Snapshot {
+    /// Every snapshot in the working path, oldest first
+    pub fn list(env: &Env) -> Result<Vec<Self>, ChurchError> {
+        let dir = PathBuf::from(&env.working_path).join(SNAPSHOTS_DIR);
+        std::fs::create_dir_all(&dir)?;
+
+        let mut snapshots = Vec::new();
+        for f in std::fs::read_dir(&dir)? {
+            let f = f?;
+            if !f.file_type()?.is_file() {
+                continue;
+            }
+            let path = f.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            if let Some(taken) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                snapshots.push(Self { taken, path });
+            }
+        }
+        snapshots.sort_by_key(|s| s.taken);
+        Ok(snapshots)
+    }
+
+    /// The newest snapshot taken at or before `time`
+    pub fn latest_before(snapshots: &[Self], time: u64) -> Option<&Self> {
+        snapshots.iter().rev().find(|s| s.taken <= time)
+    }
+
+    pub fn taken_at(&self) -> DateTime<Local> {
+        DateTime::from_timestamp(self.taken as i64, 0)
+            .unwrap_or_default()
+            .with_timezone(&Local)
+    }
+
+    /// Reads the people in the snapshot, skipping anyone who can't be read
+    pub fn load(&self) -> Result<PeopleList, ChurchError> {
+        let file = std::io::BufReader::new(std::fs::File::open(&self.path)?);
+        Ok(serde_json::from_reader(file)?)
+    }
+}
+
+impl std::fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.taken_at().format("%Y-%m-%d %H:%M"))
+    }
+}
+
+/// Someone whose person status changed
+#[derive(Clone, Debug)]
+pub struct StatusChange {
+    pub person: Person,
+    pub from: PersonStatus,
+    pub to: PersonStatus,
+}
+
+/// Someone who was moved to another area or zone
+#[derive(Clone, Debug)]
+pub struct Reassignment {
+    pub person: Person,
+    pub from_zone: Option<String>,
+    pub from_area: Option<String>,
+}
+
+/// What changed between two people lists
+#[derive(Clone, Debug, Default)]
+pub struct PeopleDiff {
+    pub new_referrals: Vec<Person>,
+    pub status_changes: Vec<StatusChange>,
+    pub reassignments: Vec<Reassignment>,
+    /// In the old list but gone from the new one
+    pub dropped: Vec<Person>,
+}
+
+impl PeopleDiff {
+    pub fn between(old: &[Person], new: &[Person]) -> Self {
+        let old_by_guid = old
+            .iter()
+            .map(|p| (p.guid.as_str(), p))
+            .collect::<HashMap<&str, &Person>>();
+        let new_by_guid = new
+            .iter()
+            .map(|p| (p.guid.as_str(), p))
+            .collect::<HashMap<&str, &Person>>();
+
+        let mut diff = Self::default();
+        for person in new {
+            let Some(was) = old_by_guid.get(person.guid.as_str()) else {
+                diff.new_referrals.push(person.clone());
+                continue;
+            };
+            if was.person_status != person.person_status {
+                diff.status_changes.push(StatusChange {
+                    person: person.clone(),
+                    from: was.person_status,
+                    to: person.person_status,
+                });
+            }
+            if was.zone_id != person.zone_id || was.area_name != person.area_name {
+                diff.reassignments.push(Reassignment {
+                    person: person.clone(),
+                    from_zone: was.zone_name.clone(),
+                    from_area: was.area_name.clone(),
+                });
+            }
+        }
+        diff.dropped = old
+            .iter()
+            .filter(|p| !new_by_guid.contains_key(p.guid.as_str()))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.new_referrals.is_empty()
+            && self.status_changes.is_empty()
+            && self.reassignments.is_empty()
+            && self.dropped.is_empty()
+    }
+}
+
+fn placement(zone: &Option<String>, area: &Option<String>) -> String {
+    format!(
+        "{} / {}",
+        zone.as_deref().unwrap_or("no zone"),
+        area.as_deref().unwrap_or("no area")
+    )
+}
+
+impl std::fmt::Display for PeopleDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "No changes");
+        }
+        if !self.new_referrals.is_empty() {
+            write!(f, "New referrals ({})", self.new_referrals.len())?;
+            for p in &self.new_referrals {
+                write!(
+                    f,
+                    "\n  - {} in {}",
+                    p.full_name(),
+                    placement(&p.zone_name, &p.area_name)
+                )?;
+            }
+            writeln!(f)?;
+        }
+        if !self.status_changes.is_empty() {
+            write!(f, "Status changes ({})", self.status_changes.len())?;
+            for c in &self.status_changes {
+                write!(f, "\n  - {}: {} -> {}", c.person.full_name(), c.from, c.to)?;
+            }
+            writeln!(f)?;
+        }
+        if !self.reassignments.is_empty() {
+            write!(f, "Reassigned ({})", self.reassignments.len())?;
+            for r in &self.reassignments {
+                write!(
+                    f,
+                    "\n  - {}: {} -> {}",
+                    r.person.full_name(),
+                    placement(&r.from_zone, &r.from_area),
+                    placement(&r.person.zone_name, &r.person.area_name)
+                )?;
+            }
+            writeln!(f)?;
+        }
+        if !self.dropped.is_empty() {
+            write!(f, "Dropped ({})", self.dropped.len())?;
+            for p in &self.dropped {
+                write!(f, "\n  - {}", p.full_name())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{self, people, person, NORTH, SOUTH};
+
+    #[test]
+    fn diff() {
+        let old = people(&[
+            person("p-1", "Ana", 10, 1, Some(NORTH), Some("Alpha"), 5),
+            person("p-2", "Ben", 10, 1, Some(NORTH), Some("Beta"), 5),
+            person("p-3", "Cy", 10, 2, Some(SOUTH), Some("Gamma"), 5),
+            person("p-5", "Eve", 10, 1, Some(SOUTH), Some("Gamma"), 5),
+        ]);
+        let new = people(&[
+            person("p-1", "Ana", 10, 2, Some(NORTH), Some("Alpha"), 5),
+            person("p-2", "Ben", 10, 20, Some(SOUTH), Some("Gamma"), 5),
+            person("p-4", "Di", 10, 1, None, None, 1),
+            person("p-5", "Eve", 10, 99, Some(SOUTH), Some("Gamma"), 5),
+        ]);
+        let diff = super::PeopleDiff::between(&old, &new);
+
+        assert_eq!(diff.new_referrals.len(), 1);
+        assert_eq!(diff.new_referrals[0].guid, "p-4");
+        assert_eq!(diff.dropped.len(), 1);
+        assert_eq!(diff.dropped[0].guid, "p-3");
+
+        let changes = diff
+            .status_changes
+            .iter()
+            .map(|c| (c.person.guid.as_str(), c.from, c.to))
+            .collect::<Vec<_>>();
+        use crate::persons::PersonStatus::*;
+        assert_eq!(
+            changes,
+            [
+                ("p-1", Yellow, Green),
+                ("p-2", Yellow, NotInterested),
+                ("p-5", Yellow, Unknown(99))
+            ]
+        );
+
+        assert_eq!(diff.reassignments.len(), 1);
+        let shown = diff.to_string();
+        assert!(shown.contains("Ben: North / Beta -> South / Gamma"));
+        assert!(shown.contains("Ana: Yellow -> Green"));
+        assert!(shown.contains("Eve: Yellow -> 99"));
+        assert!(super::PeopleDiff::between(&new, &new).is_empty());
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_listed_in_order() {
+        let server = mock::MockServer::with_fixtures().await;
+        let env = server.env();
+        let dir = std::path::PathBuf::from(&env.working_path).join(super::SNAPSHOTS_DIR);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (taken, people) in [(300, vec![]), (100, vec![mock::fixture_people().remove(0)])] {
+            let list = serde_json::json!({ "persons": people });
+            std::fs::write(dir.join(format!("{taken}.json")), list.to_string()).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "not a snapshot").unwrap();
+
+        let snapshots = super::Snapshot::list(&env).unwrap();
+        assert_eq!(
+            snapshots.iter().map(|s| s.taken).collect::<Vec<_>>(),
+            [100, 300]
+        );
+        assert_eq!(
+            super::Snapshot::latest_before(&snapshots, 299)
+                .unwrap()
+                .taken,
+            100
+        );
+        assert!(super::Snapshot::latest_before(&snapshots, 99).is_none());
+
+        let (people, _) = snapshots[0].load().unwrap().into_parts();
+        assert_eq!(people[0].first_name, "Ana");
+    }
+}